@@ -0,0 +1,230 @@
+//! Endpoints for running the server, under `/admin` and only available when an admin token is
+//! set.
+
+use crate::cache::{CacheBackend, CacheStats};
+use crate::error::PlacecageError;
+use crate::{decode, pool, ImageKind, Settings, Subject};
+use actix_web::dev::{HttpServiceFactory, Service};
+use actix_web::http::header;
+use actix_web::web::Bytes;
+use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use image::io::Reader as ImageReader;
+use image::ImageFormat;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Cursor};
+
+/// Largest source image that can be uploaded.
+const MAX_SOURCE_SIZE: usize = 32 * 1024 * 1024;
+
+/// The admin endpoints, each request to them needs the admin token.
+pub fn scope() -> impl HttpServiceFactory {
+    web::scope("/admin")
+        .app_data(web::PayloadConfig::new(MAX_SOURCE_SIZE))
+        .wrap_fn(|req, service| {
+            let settings = req
+                .app_data::<web::Data<Settings>>()
+                .expect("the settings are in the app data");
+            let call = if is_admin(req.request(), settings) {
+                Ok(service.call(req))
+            } else {
+                Err(req.into_response(unauthorized()))
+            };
+            async move {
+                match call {
+                    Ok(call) => Ok(call.await?.map_into_left_body()),
+                    Err(response) => Ok(response.map_into_right_body()),
+                }
+            }
+        })
+        .service(cache_stats_endpoint)
+        .service(pool_stats_endpoint)
+        .service(purge_cache_endpoint)
+        .service(purge_subject_cache_endpoint)
+        .service(reload_endpoint)
+        .service(list_sources_endpoint)
+        .service(put_source_endpoint)
+        .service(delete_source_endpoint)
+}
+
+/// Whether the request carries the admin token, as `Authorization: Bearer <token>` or as the
+/// password of `Authorization: Basic` whatever the user name. No request does when there is no
+/// token set.
+fn is_admin(req: &HttpRequest, settings: &Settings) -> bool {
+    let Some(token) = &settings.admin_token else {
+        return false;
+    };
+    let Some(authorization) = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+    if let Some(given) = authorization.strip_prefix("Bearer ") {
+        return constant_time_eq(given.as_bytes(), token.as_bytes());
+    }
+    authorization
+        .strip_prefix("Basic ")
+        .and_then(|credentials| BASE64.decode(credentials.trim()).ok())
+        .is_some_and(|credentials| {
+            credentials
+                .splitn(2, |&byte| byte == b':')
+                .nth(1)
+                .is_some_and(|password| constant_time_eq(password, token.as_bytes()))
+        })
+}
+
+/// Compares without returning early, so the time taken doesn't tell how much of the token was
+/// guessed right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn unauthorized() -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .insert_header((
+            header::WWW_AUTHENTICATE,
+            "Bearer, Basic realm=\"placecage\"",
+        ))
+        .finish()
+}
+
+/// Hits and misses of the caches and the images they hold, in total and by subject and kind.
+#[get("/cache/stats")]
+async fn cache_stats_endpoint(cache: web::Data<dyn CacheBackend>) -> HttpResponse {
+    let mut stats = CacheStats::default();
+    cache.stats(&mut stats);
+    HttpResponse::Ok().json(stats)
+}
+
+/// The threads making the images and the images waiting for one.
+#[get("/pool/stats")]
+async fn pool_stats_endpoint() -> HttpResponse {
+    HttpResponse::Ok().json(pool::stats())
+}
+
+/// Deletes every generated image.
+#[delete("/cache")]
+async fn purge_cache_endpoint(cache: web::Data<dyn CacheBackend>) -> io::Result<HttpResponse> {
+    cache.purge("")?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Deletes the generated images of a subject.
+#[delete("/cache/{subject}")]
+async fn purge_subject_cache_endpoint(
+    path: web::Path<Subject>,
+    cache: web::Data<dyn CacheBackend>,
+) -> io::Result<HttpResponse> {
+    cache.purge(&format!("{}/", path.into_inner()))?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Lists the source images and reads the weights files again, after changing them where the
+/// server can't watch.
+#[post("/reload")]
+async fn reload_endpoint(settings: web::Data<Settings>) -> io::Result<HttpResponse> {
+    settings.sources.reload()?;
+    settings.weights.reload()?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Numbers of the source images of each `{subject}/{kind}`.
+#[get("/sources")]
+async fn list_sources_endpoint(settings: web::Data<Settings>) -> HttpResponse {
+    let sources: BTreeMap<_, _> = settings.sources.all().into_iter().collect();
+    HttpResponse::Ok().json(sources)
+}
+
+/// Adds a source image, or replaces it, from the request body.
+#[put("/sources/{subject}/{kind}/{number}")]
+async fn put_source_endpoint(
+    path: web::Path<(Subject, ImageKind, u32)>,
+    body: Bytes,
+    settings: web::Data<Settings>,
+    cache: web::Data<dyn CacheBackend>,
+) -> Result<HttpResponse, PlacecageError> {
+    let (subject, kind, number) = path.into_inner();
+    check_upload(kind, number, &body)?;
+
+    let path = settings.sources.path(subject, kind, number);
+    fs::create_dir_all(path.parent().expect("sources are in a directory"))?;
+    // Written next to it first so a request never reads half of it.
+    let temp = path.with_extension("upload");
+    fs::write(&temp, &body)?;
+    fs::rename(&temp, &path)?;
+    source_changed(&settings, &**cache, subject, kind)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Whether `body` can be source `number` of `kind`. The sources are read by their extension, so
+/// it has to be the right kind of image.
+fn check_upload(kind: ImageKind, number: u32, body: &[u8]) -> Result<(), PlacecageError> {
+    if number == 0 {
+        return Err(PlacecageError::InvalidRequest(
+            "the sources are numbered from 1".to_string(),
+        ));
+    }
+    let extension = kind.source_extension();
+    let mut reader = ImageReader::new(Cursor::new(body)).with_guessed_format()?;
+    reader.limits(decode::limits());
+    if reader.format() != ImageFormat::from_extension(extension) {
+        return Err(PlacecageError::InvalidRequest(format!(
+            "{kind} sources have to be {extension} images"
+        )));
+    }
+    reader
+        .into_dimensions()
+        .map_err(|e| PlacecageError::InvalidRequest(format!("could not read the image: {e}")))?;
+    Ok(())
+}
+
+/// Deletes a source image.
+#[delete("/sources/{subject}/{kind}/{number}")]
+async fn delete_source_endpoint(
+    path: web::Path<(Subject, ImageKind, u32)>,
+    settings: web::Data<Settings>,
+    cache: web::Data<dyn CacheBackend>,
+) -> io::Result<HttpResponse> {
+    let (subject, kind, number) = path.into_inner();
+    fs::remove_file(settings.sources.path(subject, kind, number))?;
+    source_changed(&settings, &**cache, subject, kind)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Lists the sources of `subject` and `kind` right away instead of waiting on the watcher, and
+/// deletes their generated images. They'd no longer be served anyway, as sizes get other sources
+/// once they change.
+fn source_changed(
+    settings: &Settings,
+    cache: &dyn CacheBackend,
+    subject: Subject,
+    kind: ImageKind,
+) -> io::Result<()> {
+    settings.sources.reload()?;
+    cache.purge(&format!("{subject}/{kind}/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::ResponseError;
+
+    #[test]
+    fn bad_uploads_are_the_request_fault() {
+        let error = check_upload(ImageKind::Default, 0, b"").unwrap_err();
+        assert_eq!(error.status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(error.to_string(), "the sources are numbered from 1");
+
+        let error = check_upload(ImageKind::Default, 1, b"GIF89a").unwrap_err();
+        assert_eq!(error.status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(error.to_string(), "default sources have to be jpg images");
+
+        let error = check_upload(ImageKind::Default, 1, b"not an image").unwrap_err();
+        assert_eq!(error.status_code(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,98 @@
+//! Frame by frame resizing for the animated gif sources.
+
+use crate::decode;
+use crate::effects::Effects;
+use crate::resize::Resize;
+use crate::{Encoding, OutputFormat};
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::error::{EncodingError, ImageFormatHint, UnsupportedError, UnsupportedErrorKind};
+use image::{AnimationDecoder, DynamicImage, Frame, ImageError, ImageFormat};
+use std::path::Path;
+
+/// Color quantization speed for the gif encoder, from 1 (best) to 30 (fastest). The encoder
+/// default of 1 takes seconds per frame on the bigger sizes.
+const GIF_ENCODE_SPEED: i32 = 10;
+
+/// Resizes every frame of the gif at `input_path` and applies `effects` to it, keeping the
+/// original frame delays, and encodes the result as a looping animation. Only the gif and webp
+/// formats can hold an animation.
+pub fn resize_animation(
+    input_path: &Path,
+    resize: Resize,
+    effects: &Effects,
+    encoding: Encoding,
+) -> Result<Vec<u8>, ImageError> {
+    let decoder = decode::gif(input_path)?;
+    let frames = decoder.into_frames().map(|frame| {
+        let frame = frame?;
+        let delay = frame.delay();
+        let oriented = effects.orient(DynamicImage::ImageRgba8(frame.into_buffer()));
+        let resized = resize.apply(&oriented, resize.filter);
+        let resized = effects.apply(resized).into_rgba8();
+        Ok(Frame::from_parts(resized, 0, 0, delay))
+    });
+
+    match encoding.format {
+        OutputFormat::Gif => encode_gif(frames),
+        OutputFormat::Webp => encode_webp(frames, encoding.quality),
+        format => Err(ImageError::Unsupported(
+            UnsupportedError::from_format_and_kind(
+                ImageFormat::from(format).into(),
+                UnsupportedErrorKind::GenericFeature("animation".to_string()),
+            ),
+        )),
+    }
+}
+
+fn encode_gif(
+    frames: impl Iterator<Item = Result<Frame, ImageError>>,
+) -> Result<Vec<u8>, ImageError> {
+    let mut encoded = Vec::new();
+    let mut encoder = GifEncoder::new_with_speed(&mut encoded, GIF_ENCODE_SPEED);
+    encoder.set_repeat(Repeat::Infinite)?;
+    encoder.try_encode_frames(frames)?;
+    // The gif trailer is only written when the encoder is dropped.
+    drop(encoder);
+    Ok(encoded)
+}
+
+fn encode_webp(
+    frames: impl Iterator<Item = Result<Frame, ImageError>>,
+    quality: Option<u8>,
+) -> Result<Vec<u8>, ImageError> {
+    let webp_error = |message: String| {
+        ImageError::Encoding(EncodingError::new(
+            ImageFormatHint::Exact(ImageFormat::WebP),
+            message,
+        ))
+    };
+
+    let frames = frames.collect::<Result<Vec<_>, _>>()?;
+    // Fitting instead of filling can make the frames smaller than the requested size.
+    let (width, height) = frames
+        .first()
+        .map_or((0, 0), |frame| frame.buffer().dimensions());
+    let mut config = webp::WebPConfig::new()
+        .map_err(|_| webp_error("could not initialize the webp encoder".to_string()))?;
+    if let Some(quality) = quality {
+        config.quality = f32::from(quality);
+    }
+
+    let mut encoder = webp::AnimEncoder::new(width, height, &config);
+    // Frames are placed by their start time, gif frames only know their own duration.
+    let mut timestamp = 0;
+    for frame in &frames {
+        encoder.add_frame(webp::AnimFrame::from_rgba(
+            frame.buffer().as_raw(),
+            width,
+            height,
+            timestamp,
+        ));
+        let (numerator, denominator) = frame.delay().numer_denom_ms();
+        timestamp += (numerator / denominator.max(1)) as i32;
+    }
+    let encoded = encoder
+        .try_encode()
+        .map_err(|e| webp_error(format!("{e:?}")))?;
+    Ok(encoded.to_vec())
+}
@@ -0,0 +1,888 @@
+//! Where generated images are kept: on disk, in memory for the most requested ones, or in Redis
+//! to share them between instances. Which ones is picked at startup.
+
+#[cfg(unix)]
+use crate::hash;
+use actix_web::web::Bytes;
+#[cfg(feature = "redis")]
+use redis::Commands;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// A place generated images are kept in, keyed by their path in the generated images directory,
+/// which already spells out everything the image was made with.
+pub trait CacheBackend: Send + Sync {
+    /// The image under `key`, `None` when it isn't there or can't be read.
+    fn get(&self, key: &str) -> Option<Cached>;
+    /// Keeps the image under `key`, along with when it was made when the cache can keep that.
+    fn insert(&self, key: &str, cached: &Cached) -> io::Result<()>;
+    /// Deletes the images whose key starts with `prefix`, all of them when it's empty.
+    fn purge(&self, prefix: &str) -> io::Result<()>;
+    /// Adds what the cache holds to `stats`.
+    fn stats(&self, stats: &mut CacheStats);
+    /// Writes out what the cache only keeps in memory and needs back after a restart, when the
+    /// server stops.
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// An image found in a cache, or to put in one.
+#[derive(Clone)]
+pub struct Cached {
+    pub bytes: Bytes,
+    /// When it was put in the cache it was found in, which is when it was made or later.
+    pub made: SystemTime,
+    /// Name of the cache it was found in, like in `PLACECAGE_CACHE`. `None` when it was just
+    /// made.
+    pub found_in: Option<&'static str>,
+}
+
+/// What the caches did and hold, for the admin endpoint.
+#[derive(Default, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Images held by each cache, by its name in `PLACECAGE_CACHE`.
+    pub caches: BTreeMap<&'static str, Usage>,
+    /// The same by subject and kind, like `cage/default`.
+    pub subjects: BTreeMap<String, SubjectStats>,
+}
+
+#[derive(Default, Serialize)]
+pub struct SubjectStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub caches: BTreeMap<&'static str, Usage>,
+}
+
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct Usage {
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+impl Usage {
+    fn add(&mut self, bytes: u64) {
+        self.entries += 1;
+        self.bytes += bytes;
+    }
+}
+
+impl CacheStats {
+    /// Counts the image under `key` as held by `cache`.
+    fn add_entry(&mut self, cache: &'static str, key: &str, bytes: u64) {
+        self.caches.entry(cache).or_default().add(bytes);
+        self.subject(key)
+            .caches
+            .entry(cache)
+            .or_default()
+            .add(bytes);
+    }
+
+    fn subject(&mut self, key: &str) -> &mut SubjectStats {
+        self.subjects
+            .entry(subject_of(key).to_string())
+            .or_default()
+    }
+}
+
+/// The subject and kind directories of the image under `key`, like `cage/default`.
+fn subject_of(key: &str) -> &str {
+    match key.match_indices('/').nth(1) {
+        Some((end, _)) => &key[..end],
+        None => key,
+    }
+}
+
+/// The cache backends that can be picked with `PLACECAGE_CACHE`.
+#[derive(Clone, Copy, PartialEq)]
+pub enum CacheKind {
+    Memory,
+    Fs,
+    Redis,
+}
+
+impl str::FromStr for CacheKind {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(CacheKind::Memory),
+            "fs" => Ok(CacheKind::Fs),
+            "redis" => Ok(CacheKind::Redis),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown cache `{s}`, expected memory, fs or redis"),
+            )),
+        }
+    }
+}
+
+/// How the caches are set up, read with the rest of the settings.
+#[derive(Clone)]
+pub struct CacheConfig {
+    /// Looked up in this order.
+    pub kinds: Vec<CacheKind>,
+    /// Bytes of images the memory cache keeps.
+    pub memory_size: u64,
+    /// Generated images directory the filesystem cache keeps the images in.
+    pub dir: PathBuf,
+    /// Bytes of images the filesystem cache keeps, the least recently used ones are deleted past
+    /// it. No limit when `None`. Images stored once for several keys count once for each.
+    pub disk_quota: Option<u64>,
+    /// How long an image is kept before being made again, so changes the key doesn't tell, like
+    /// new weights, eventually show up. Forever when `None`.
+    pub max_age: Option<Duration>,
+    pub redis_url: Option<String>,
+}
+
+/// Opens the caches in `config`. With none the images are made again on every request.
+pub fn open(config: &CacheConfig) -> io::Result<Arc<dyn CacheBackend>> {
+    let mut tiers = Vec::<Box<dyn CacheBackend>>::new();
+    for kind in &config.kinds {
+        tiers.push(match kind {
+            CacheKind::Memory => Box::new(MemoryCache::new(config.memory_size, config.max_age)),
+            CacheKind::Fs => Box::new(FsCache::open(
+                config.dir.clone(),
+                config.disk_quota,
+                config.max_age,
+            )?),
+            CacheKind::Redis => {
+                let url = config.redis_url.as_deref().ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "the redis cache needs PLACECAGE_REDIS_URL",
+                    )
+                })?;
+                redis_backend(url, config.max_age)?
+            }
+        });
+    }
+    let cache: Box<dyn CacheBackend> = match tiers.len() {
+        0 => Box::new(NoCache),
+        1 => tiers.pop().unwrap(),
+        _ => Box::new(Tiered(tiers)),
+    };
+    Ok(Arc::new(Counted {
+        inner: cache,
+        hits: AtomicU64::new(0),
+        misses: AtomicU64::new(0),
+        by_subject: Mutex::default(),
+    }))
+}
+
+#[cfg(feature = "redis")]
+fn redis_backend(url: &str, max_age: Option<Duration>) -> io::Result<Box<dyn CacheBackend>> {
+    let redis = RedisCache::connect(url, max_age)
+        .map_err(|e| io::Error::other(format!("could not connect to Redis at {url}: {e}")))?;
+    Ok(Box::new(redis))
+}
+
+#[cfg(not(feature = "redis"))]
+fn redis_backend(_url: &str, _max_age: Option<Duration>) -> io::Result<Box<dyn CacheBackend>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Redis support requires building with the `redis` feature",
+    ))
+}
+
+/// Counts the hits and misses of the caches it wraps, in total and by subject and kind.
+struct Counted {
+    inner: Box<dyn CacheBackend>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    /// Hits and misses.
+    by_subject: Mutex<HashMap<String, (u64, u64)>>,
+}
+
+impl CacheBackend for Counted {
+    fn get(&self, key: &str) -> Option<Cached> {
+        let cached = self.inner.get(key);
+        let mut by_subject = self.by_subject.lock().unwrap();
+        let (hits, misses) = by_subject.entry(subject_of(key).to_string()).or_default();
+        if cached.is_some() {
+            *hits += 1;
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            *misses += 1;
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        cached
+    }
+
+    fn insert(&self, key: &str, cached: &Cached) -> io::Result<()> {
+        self.inner.insert(key, cached)
+    }
+
+    fn purge(&self, prefix: &str) -> io::Result<()> {
+        self.inner.purge(prefix)
+    }
+
+    fn stats(&self, stats: &mut CacheStats) {
+        stats.hits += self.hits.load(Ordering::Relaxed);
+        stats.misses += self.misses.load(Ordering::Relaxed);
+        for (subject, (hits, misses)) in self.by_subject.lock().unwrap().iter() {
+            let subject = stats.subject(subject);
+            subject.hits += hits;
+            subject.misses += misses;
+        }
+        self.inner.stats(stats);
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Several caches looked up in order, an image found in one of them is copied to the ones before
+/// it so the next request finds it sooner.
+struct Tiered(Vec<Box<dyn CacheBackend>>);
+
+impl CacheBackend for Tiered {
+    fn get(&self, key: &str) -> Option<Cached> {
+        for (index, tier) in self.0.iter().enumerate() {
+            if let Some(cached) = tier.get(key) {
+                for faster in &self.0[..index] {
+                    // The image is already on its way to the client either way.
+                    let _ = faster.insert(key, &cached);
+                }
+                return Some(cached);
+            }
+        }
+        None
+    }
+
+    fn insert(&self, key: &str, cached: &Cached) -> io::Result<()> {
+        for tier in &self.0 {
+            tier.insert(key, cached)?;
+        }
+        Ok(())
+    }
+
+    fn purge(&self, prefix: &str) -> io::Result<()> {
+        for tier in &self.0 {
+            tier.purge(prefix)?;
+        }
+        Ok(())
+    }
+
+    fn stats(&self, stats: &mut CacheStats) {
+        for tier in &self.0 {
+            tier.stats(stats);
+        }
+    }
+
+    /// Every tier is flushed even when one fails, the first error is returned.
+    fn flush(&self) -> io::Result<()> {
+        let mut result = Ok(());
+        for tier in &self.0 {
+            result = result.and(tier.flush());
+        }
+        result
+    }
+}
+
+/// Doesn't keep anything.
+struct NoCache;
+
+impl CacheBackend for NoCache {
+    fn get(&self, _key: &str) -> Option<Cached> {
+        None
+    }
+
+    fn insert(&self, _key: &str, _cached: &Cached) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn purge(&self, _prefix: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn stats(&self, _stats: &mut CacheStats) {}
+}
+
+/// How often the filesystem cache checks whether it went over its quota.
+const EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Images are written to a file ending with this first and renamed into place once complete, so
+/// no one reads a half written image.
+const TEMP_SUFFIX: &str = ".tmp";
+/// Temporary files older than this are left from a write that was interrupted, writing an image
+/// doesn't take nearly as long.
+const STALE_TEMP_AGE: Duration = Duration::from_secs(60);
+/// Directory of the generated images directory the images are stored in by the hash of their
+/// content, the files of the keys are hard links to them so images that come out the same, like
+/// a gray image and its grayscale filter version, are only stored once. Not used with a max age,
+/// the links share the time the blob was written and each image expires on its own.
+const BLOBS_DIR: &str = "_blobs";
+/// File of the generated images directory the keys are written to from the least to the most
+/// recently used when the server stops, so the next one evicts the same images first. Without it
+/// the files are taken as used in the order they were made.
+const RECENCY_FILE: &str = "_recency";
+
+/// Files in the generated images directory, they outlive restarts.
+struct FsCache {
+    dir: PathBuf,
+    /// Sizes of the files, to know what to delete when there is a quota.
+    index: Arc<Mutex<Lru<()>>>,
+    max_age: Option<Duration>,
+    /// Tells apart the temporary files of the writes going on at the same time.
+    writes: AtomicU64,
+}
+
+impl FsCache {
+    /// Indexes the files already in `dir`, the oldest ones first, and with a `quota` starts the
+    /// background thread deleting the least recently used files past it. Temporary files left by
+    /// interrupted writes are deleted.
+    fn open(dir: PathBuf, quota: Option<u64>, max_age: Option<Duration>) -> io::Result<Self> {
+        let mut files = Vec::new();
+        list_files(&dir, &dir, &mut files)?;
+        files.retain(|(key, _, modified)| {
+            if !key.ends_with(TEMP_SUFFIX) {
+                return true;
+            }
+            // The recent ones may be written by another instance sharing the directory.
+            if is_expired(*modified, STALE_TEMP_AGE) {
+                let _ = fs::remove_file(dir.join(key));
+            }
+            false
+        });
+        files.sort_by_key(|(_, _, modified)| *modified);
+        // The files left out of it are older than the ones in it or made by another instance
+        // since, either way they're the first to go.
+        let recency = fs::read_to_string(dir.join(RECENCY_FILE)).unwrap_or_default();
+        let positions: HashMap<&str, usize> = recency
+            .lines()
+            .enumerate()
+            .map(|(n, key)| (key, n))
+            .collect();
+        files.sort_by_key(|(key, _, _)| positions.get(key.as_str()).copied());
+        let mut index = Lru::default();
+        for (key, size, _) in files {
+            index.insert(key, (), size);
+        }
+        let index = Arc::new(Mutex::new(index));
+        #[cfg(unix)]
+        remove_orphan_blobs(&dir);
+
+        if let Some(quota) = quota {
+            let (dir, index) = (dir.clone(), Arc::clone(&index));
+            thread::spawn(move || loop {
+                evict(&dir, &index, quota);
+                #[cfg(unix)]
+                remove_orphan_blobs(&dir);
+                thread::sleep(EVICTION_INTERVAL);
+            });
+        }
+        Ok(FsCache {
+            dir,
+            index,
+            max_age,
+            writes: AtomicU64::new(0),
+        })
+    }
+
+    /// Makes the file at `path` with `write`, which is given a temporary path to write it at
+    /// before it's renamed into place.
+    fn put(&self, path: &Path, write: impl FnOnce(&Path) -> io::Result<()>) -> io::Result<()> {
+        let count = self.writes.fetch_add(1, Ordering::Relaxed);
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(format!(".{}-{count}{TEMP_SUFFIX}", process::id()));
+        let temp = PathBuf::from(temp);
+        let written = write(&temp).and_then(|()| fs::rename(&temp, path));
+        // Renaming a link over another link of the same file succeeds without doing anything,
+        // the temporary file is still there then.
+        let _ = fs::remove_file(&temp);
+        written
+    }
+
+    /// The file in the blobs directory holding `bytes`, written there when it isn't yet. `None`
+    /// when it can't be, or in the unlikely case other bytes have the same hash.
+    #[cfg(unix)]
+    fn blob(&self, bytes: &[u8]) -> Option<PathBuf> {
+        let blobs = self.dir.join(BLOBS_DIR);
+        let path = blobs.join(format!("{:016x}", hash::stable_hash(bytes)));
+        match fs::read(&path) {
+            Ok(existing) => return (existing == bytes).then_some(path),
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return None,
+            Err(_) => {}
+        }
+        fs::create_dir_all(blobs).ok()?;
+        self.put(&path, |temp| fs::write(temp, bytes)).ok()?;
+        Some(path)
+    }
+}
+
+impl CacheBackend for FsCache {
+    fn get(&self, key: &str) -> Option<Cached> {
+        let path = self.dir.join(key);
+        let modified = fs::metadata(&path).and_then(|m| m.modified()).ok()?;
+        if self
+            .max_age
+            .is_some_and(|max_age| is_expired(modified, max_age))
+        {
+            return None;
+        }
+        let bytes = fs::read(path).ok()?;
+        let mut index = self.index.lock().unwrap();
+        // Another instance sharing the directory may have made it.
+        if index.touch(key).is_none() {
+            index.insert(key.to_string(), (), bytes.len() as u64);
+        }
+        Some(Cached {
+            bytes: Bytes::from(bytes),
+            made: modified,
+            found_in: Some("fs"),
+        })
+    }
+
+    /// The file gets the time it's written at. Without a max age it's a link to the blob of its
+    /// bytes, and its time is when the blob was first written.
+    fn insert(&self, key: &str, cached: &Cached) -> io::Result<()> {
+        let bytes = &cached.bytes;
+        let path = self.dir.join(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        #[cfg(unix)]
+        let blob = match self.max_age {
+            Some(_) => None,
+            None => self.blob(bytes),
+        };
+        #[cfg(unix)]
+        if blob.as_deref().is_some_and(|blob| same_file(blob, &path)) {
+            // Linked already, renaming another link of it over the key wouldn't do anything.
+            self.index
+                .lock()
+                .unwrap()
+                .insert(key.to_string(), (), bytes.len() as u64);
+            return Ok(());
+        }
+        self.put(&path, |temp| {
+            #[cfg(unix)]
+            if let Some(blob) = &blob {
+                // The blob can be deleted as an orphan before it's linked, then it's written out
+                // in full like on the other platforms.
+                if fs::hard_link(blob, temp).is_ok() {
+                    return Ok(());
+                }
+            }
+            fs::write(temp, bytes)
+        })?;
+        self.index
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), (), bytes.len() as u64);
+        Ok(())
+    }
+
+    /// The prefixes are whole directories, like `cage/`.
+    fn purge(&self, prefix: &str) -> io::Result<()> {
+        // Held all along so no image is indexed while its directory is going away.
+        let mut index = self.index.lock().unwrap();
+        match fs::remove_dir_all(self.dir.join(prefix)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        index.remove_prefix(prefix);
+        #[cfg(unix)]
+        remove_orphan_blobs(&self.dir);
+        Ok(())
+    }
+
+    /// From the index, files another instance sharing the directory made only show up once
+    /// they're requested from this one.
+    fn stats(&self, stats: &mut CacheStats) {
+        for (key, size) in self.index.lock().unwrap().sizes() {
+            stats.add_entry("fs", key, size);
+        }
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let mut recency = String::new();
+        for key in self.index.lock().unwrap().by_recency() {
+            recency.push_str(key);
+            recency.push('\n');
+        }
+        fs::create_dir_all(&self.dir)?;
+        self.put(&self.dir.join(RECENCY_FILE), |temp| {
+            fs::write(temp, recency)
+        })
+    }
+}
+
+/// Deletes the least recently used files in `dir` until they fit in `quota` bytes.
+fn evict(dir: &Path, index: &Mutex<Lru<()>>, quota: u64) {
+    loop {
+        let Some(key) = index.lock().unwrap().pop_over(quota) else {
+            return;
+        };
+        // Gone already is as good as deleted, anything else will be tried again next time.
+        let _ = fs::remove_file(dir.join(key));
+    }
+}
+
+/// Whether `a` and `b` are links to the same file.
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+/// Deletes the blobs in `dir` no key links to anymore.
+#[cfg(unix)]
+fn remove_orphan_blobs(dir: &Path) {
+    use std::os::unix::fs::MetadataExt;
+
+    let Ok(entries) = fs::read_dir(dir.join(BLOBS_DIR)) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if entry.metadata().is_ok_and(|metadata| metadata.nlink() == 1) {
+            let _ = fs::remove_file(entry.path());
+        }
+    }
+}
+
+/// Adds the files under `dir` to `files`, as their path relative to `root`, size and last
+/// modification time. The blobs are left out, they're only reached through the keys, and so is
+/// the recency file.
+fn list_files(
+    root: &Path,
+    dir: &Path,
+    files: &mut Vec<(String, u64, SystemTime)>,
+) -> io::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            if entry.path() != root.join(BLOBS_DIR) {
+                list_files(root, &entry.path(), files)?;
+            }
+        } else if entry.path() == root.join(RECENCY_FILE) {
+            continue;
+        } else if let Ok(key) = entry.path().strip_prefix(root) {
+            files.push((
+                key.to_string_lossy().into_owned(),
+                metadata.len(),
+                metadata.modified()?,
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Least recently used cache of encoded images.
+struct MemoryCache {
+    /// Total size of the images kept, in bytes. Nothing is kept when it's 0.
+    capacity: u64,
+    max_age: Option<Duration>,
+    /// The images with when they were made.
+    inner: Mutex<Lru<(Bytes, SystemTime)>>,
+}
+
+impl MemoryCache {
+    fn new(capacity: u64, max_age: Option<Duration>) -> Self {
+        MemoryCache {
+            capacity,
+            max_age,
+            inner: Mutex::default(),
+        }
+    }
+}
+
+impl CacheBackend for MemoryCache {
+    fn get(&self, key: &str) -> Option<Cached> {
+        let mut lru = self.inner.lock().unwrap();
+        let (bytes, made) = lru.touch(key)?;
+        // Expired images are left for the next insert to replace or the eviction to clear.
+        if self
+            .max_age
+            .is_some_and(|max_age| is_expired(*made, max_age))
+        {
+            return None;
+        }
+        Some(Cached {
+            bytes: bytes.clone(),
+            made: *made,
+            found_in: Some("memory"),
+        })
+    }
+
+    /// Keeps the image under `key`, evicting the least recently used images to make room. Images
+    /// bigger than the whole cache aren't kept.
+    fn insert(&self, key: &str, cached: &Cached) -> io::Result<()> {
+        let size = cached.bytes.len() as u64;
+        if size > self.capacity {
+            return Ok(());
+        }
+        let mut lru = self.inner.lock().unwrap();
+        lru.insert(key.to_string(), (cached.bytes.clone(), cached.made), size);
+        while lru.pop_over(self.capacity).is_some() {}
+        Ok(())
+    }
+
+    fn purge(&self, prefix: &str) -> io::Result<()> {
+        self.inner.lock().unwrap().remove_prefix(prefix);
+        Ok(())
+    }
+
+    fn stats(&self, stats: &mut CacheStats) {
+        for (key, size) in self.inner.lock().unwrap().sizes() {
+            stats.add_entry("memory", key, size);
+        }
+    }
+}
+
+/// Whether something made at `made` is older than `max_age`. Times in the future, from a clock
+/// that went back, count as just made.
+fn is_expired(made: SystemTime, max_age: Duration) -> bool {
+    made.elapsed().is_ok_and(|age| age > max_age)
+}
+
+/// Values by key along with how much room they take, in the order they were last used.
+struct Lru<V> {
+    entries: HashMap<String, Entry<V>>,
+    /// Keys by the tick they were last used at, the first one is the next to be evicted.
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+    /// Sum of the sizes of the entries.
+    size: u64,
+}
+
+struct Entry<V> {
+    value: V,
+    size: u64,
+    last_used: u64,
+}
+
+impl<V> Default for Lru<V> {
+    fn default() -> Self {
+        Lru {
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            size: 0,
+        }
+    }
+}
+
+impl<V> Lru<V> {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    /// The value under `key`, which becomes the most recently used.
+    fn touch(&mut self, key: &str) -> Option<&V> {
+        let tick = self.next_tick();
+        let entry = self.entries.get_mut(key)?;
+        let previous = std::mem::replace(&mut entry.last_used, tick);
+        self.recency.remove(&previous);
+        self.recency.insert(tick, key.to_string());
+        Some(&entry.value)
+    }
+
+    fn insert(&mut self, key: String, value: V, size: u64) {
+        let tick = self.next_tick();
+        if let Some(previous) = self.entries.remove(&key) {
+            self.recency.remove(&previous.last_used);
+            self.size -= previous.size;
+        }
+        self.size += size;
+        self.recency.insert(tick, key.clone());
+        self.entries.insert(
+            key,
+            Entry {
+                value,
+                size,
+                last_used: tick,
+            },
+        );
+    }
+
+    /// Takes out the least recently used entry while the total size is over `capacity`.
+    fn pop_over(&mut self, capacity: u64) -> Option<String> {
+        if self.size <= capacity {
+            return None;
+        }
+        let (_, key) = self.recency.pop_first()?;
+        if let Some(entry) = self.entries.remove(&key) {
+            self.size -= entry.size;
+        }
+        Some(key)
+    }
+
+    /// The keys with the room their value takes.
+    fn sizes(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.entries
+            .iter()
+            .map(|(key, entry)| (key.as_str(), entry.size))
+    }
+
+    /// The keys from the least to the most recently used.
+    fn by_recency(&self) -> impl Iterator<Item = &str> {
+        self.recency.values().map(String::as_str)
+    }
+
+    fn remove_prefix(&mut self, prefix: &str) {
+        let Lru {
+            entries,
+            recency,
+            size,
+            ..
+        } = self;
+        entries.retain(|key, entry| {
+            let keep = !key.starts_with(prefix);
+            if !keep {
+                recency.remove(&entry.last_used);
+                *size -= entry.size;
+            }
+            keep
+        });
+    }
+}
+
+/// Keys of the images in Redis start with this, so the server can be shared with other data.
+#[cfg(feature = "redis")]
+const REDIS_KEY_PREFIX: &str = "placecage:";
+/// The images are stored in Redis after when they were made, as big endian seconds since the
+/// epoch in this many bytes.
+#[cfg(feature = "redis")]
+const REDIS_MADE_SIZE: usize = 8;
+/// A Redis server that stopped answering shouldn't hold the requests for long, they can still
+/// make the image themselves.
+#[cfg(feature = "redis")]
+const REDIS_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Images shared with the other instances using the same Redis server. It's only a cache, so
+/// when Redis fails the image is just made again instead of failing the request.
+#[cfg(feature = "redis")]
+struct RedisCache {
+    client: redis::Client,
+    /// Connections not in use, a new one is opened when they all are.
+    idle: Mutex<Vec<redis::Connection>>,
+    /// Left to Redis to expire the images.
+    max_age: Option<Duration>,
+}
+
+#[cfg(feature = "redis")]
+impl RedisCache {
+    fn connect(url: &str, max_age: Option<Duration>) -> redis::RedisResult<Self> {
+        let client = redis::Client::open(url)?;
+        let cache = RedisCache {
+            client,
+            idle: Mutex::default(),
+            max_age,
+        };
+        let connection = cache.open()?;
+        cache.idle.lock().unwrap().push(connection);
+        Ok(cache)
+    }
+
+    fn open(&self) -> redis::RedisResult<redis::Connection> {
+        let connection = self.client.get_connection_with_timeout(REDIS_TIMEOUT)?;
+        connection.set_read_timeout(Some(REDIS_TIMEOUT))?;
+        connection.set_write_timeout(Some(REDIS_TIMEOUT))?;
+        Ok(connection)
+    }
+
+    fn with_connection<T>(
+        &self,
+        command: impl FnOnce(&mut redis::Connection) -> redis::RedisResult<T>,
+    ) -> redis::RedisResult<T> {
+        let idle = self.idle.lock().unwrap().pop();
+        let mut connection = match idle {
+            Some(connection) => connection,
+            None => self.open()?,
+        };
+        let result = command(&mut connection);
+        // A connection that failed may be broken, the next request opens a new one.
+        if result.is_ok() {
+            self.idle.lock().unwrap().push(connection);
+        }
+        result
+    }
+}
+
+#[cfg(feature = "redis")]
+impl CacheBackend for RedisCache {
+    fn get(&self, key: &str) -> Option<Cached> {
+        let key = format!("{REDIS_KEY_PREFIX}{key}");
+        let value = self
+            .with_connection(|connection| {
+                redis::cmd("GET")
+                    .arg(&key)
+                    .query::<Option<Vec<u8>>>(connection)
+            })
+            .ok()
+            .flatten()?;
+        if value.len() < REDIS_MADE_SIZE {
+            return None;
+        }
+        let mut bytes = Bytes::from(value);
+        let made = bytes.split_to(REDIS_MADE_SIZE);
+        let made = u64::from_be_bytes(made.as_ref().try_into().unwrap());
+        Some(Cached {
+            bytes,
+            made: SystemTime::UNIX_EPOCH + Duration::from_secs(made),
+            found_in: Some("redis"),
+        })
+    }
+
+    fn insert(&self, key: &str, cached: &Cached) -> io::Result<()> {
+        let key = format!("{REDIS_KEY_PREFIX}{key}");
+        let made = cached
+            .made
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let mut value = Vec::with_capacity(REDIS_MADE_SIZE + cached.bytes.len());
+        value.extend_from_slice(&made.to_be_bytes());
+        value.extend_from_slice(&cached.bytes);
+        let mut command = redis::cmd("SET");
+        command.arg(&key).arg(value);
+        if let Some(max_age) = self.max_age {
+            // Redis refuses an expiry of 0.
+            command.arg("EX").arg(max_age.as_secs().max(1));
+        }
+        let _ = self.with_connection(|connection| command.query::<()>(connection));
+        Ok(())
+    }
+
+    fn purge(&self, prefix: &str) -> io::Result<()> {
+        let pattern = format!("{REDIS_KEY_PREFIX}{prefix}*");
+        self.with_connection(|connection| {
+            let keys = connection
+                .scan_match::<_, String>(&pattern)?
+                .collect::<Vec<_>>();
+            // Deleted in batches, a single DEL with every key could block Redis for a while.
+            for batch in keys.chunks(1000) {
+                redis::cmd("DEL").arg(batch).query::<()>(connection)?;
+            }
+            Ok(())
+        })
+        .map_err(io::Error::other)
+    }
+
+    /// Left out, going through every key could keep Redis busy for a while.
+    fn stats(&self, _stats: &mut CacheStats) {}
+}
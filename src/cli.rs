@@ -0,0 +1,107 @@
+//! The command line, the server is one of the commands and the others run the same image code
+//! without it.
+
+use crate::cache;
+use crate::warm::{self, PregenArgs};
+use crate::{signing, Settings};
+use clap::{Args, Parser, Subcommand};
+use std::io;
+use std::net::IpAddr;
+
+#[derive(Parser)]
+#[command(
+    about = "Serves placeholder pictures of celebrities at any size",
+    args_conflicts_with_subcommands = true
+)]
+pub struct Cli {
+    /// Runs the server when left out.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// The options of `serve`, which can be given without it.
+    #[command(flatten)]
+    pub serve: ServeArgs,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Runs the server.
+    Serve(ServeArgs),
+    /// Makes the images of the usual sizes into the generated images directory, to ship them with
+    /// the server.
+    Pregen(PregenArgs),
+    /// Checks the configuration and that every source picture can be read.
+    Validate,
+    /// Deletes the generated images.
+    CleanCache(CleanCacheArgs),
+    /// Prints the URLs with their signature.
+    Sign {
+        /// Paths with their query, like /300/200?grayscale=1.
+        #[arg(required = true)]
+        urls: Vec<String>,
+    },
+}
+
+#[derive(Args)]
+pub struct ServeArgs {
+    /// Address to listen on, can be given several times. PLACECAGE_BIND_ADDR by default.
+    #[arg(long)]
+    pub host: Vec<IpAddr>,
+    /// Port to listen on, the same for every host.
+    #[arg(long)]
+    pub port: Option<u16>,
+}
+
+#[derive(Args)]
+pub struct CleanCacheArgs {
+    /// Only the images of these subjects, like cage,murray.
+    #[arg(long)]
+    subjects: Option<String>,
+}
+
+/// Reads every source picture far enough to know its size, so the broken ones show up before
+/// they're asked for, and checks none are missing.
+pub fn validate(settings: &Settings) -> io::Result<()> {
+    let problems = settings.sources.check();
+    for problem in &problems {
+        eprintln!("{problem}");
+    }
+    let count: usize = settings.sources.all().values().map(Vec::len).sum();
+    match problems.len() {
+        0 => {
+            println!("the configuration and the {count} source pictures are fine");
+            Ok(())
+        }
+        found => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("found {found} problems with the {count} source pictures"),
+        )),
+    }
+}
+
+/// Deletes the generated images from the caches that outlive the server.
+pub fn clean_cache(settings: &Settings, args: CleanCacheArgs) -> io::Result<()> {
+    let cache = cache::open(&settings.cache)?;
+    match args.subjects {
+        Some(subjects) => {
+            for subject in warm::parse_subjects(&subjects)? {
+                cache.purge(&format!("{subject}/"))?;
+            }
+        }
+        None => cache.purge("")?,
+    }
+    Ok(())
+}
+
+/// Prints the `urls` with their signature.
+pub fn sign(settings: &Settings, urls: &[String]) -> io::Result<()> {
+    let Some(key) = &settings.signing_key else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "sign needs PLACECAGE_SIGNING_KEY",
+        ));
+    };
+    for url in urls {
+        println!("{}", signing::sign(key, url)?);
+    }
+    Ok(())
+}
@@ -0,0 +1,157 @@
+//! The configuration file, `config.toml` in the working directory or the file in
+//! `PLACECAGE_CONFIG`. Everything in it is optional, and the environment variables win over it.
+
+use crate::Subject;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::{env, fs, io};
+
+/// Read when `PLACECAGE_CONFIG` isn't set, it's fine for it not to be there.
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub paths: Paths,
+    pub limits: Limits,
+    pub cache: Cache,
+    pub server: Server,
+    /// The subjects served, all of them when it's left out.
+    pub subjects: Option<Vec<Subject>>,
+    /// Headers added to every response, over the security headers sent by default. An empty
+    /// value leaves the header out.
+    pub headers: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Paths {
+    /// Directory of the source pictures, in `{subject}/{kind}` directories.
+    pub sources: PathBuf,
+    /// Directory the generated images are kept in.
+    pub generated: PathBuf,
+    /// Directory of the fonts that can be picked with `?font=`.
+    pub fonts: Option<PathBuf>,
+    /// Picture shown at the size asked for when there is none of the subject or kind, to the
+    /// requests getting their errors as images.
+    pub not_found_image: Option<PathBuf>,
+}
+
+impl Default for Paths {
+    fn default() -> Self {
+        Paths {
+            sources: PathBuf::from("public/images/source"),
+            generated: PathBuf::from("public/images/_gen"),
+            fonts: None,
+            not_found_image: None,
+        }
+    }
+}
+
+/// How big the images made can get.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Widest image that can be asked for.
+    pub max_width: u32,
+    /// Tallest image that can be asked for.
+    pub max_height: u32,
+    /// Largest width times height that can be asked for.
+    pub max_pixels: u64,
+    /// Width plus height past which the images are resized with the nearest pixel instead of
+    /// Catmull-Rom, which gets slow on them.
+    pub fast_filter_above: u32,
+    /// Seconds an image gets to be made before the request gives up on it with a 503, no limit
+    /// when it's left out.
+    pub generation_timeout: Option<u64>,
+    /// Images made at once, the next ones wait for their turn. No limit when it's left out.
+    pub max_generations: Option<usize>,
+    /// Images that can wait for their turn, the requests past it get a 503. No limit when it's
+    /// left out.
+    pub generation_queue: Option<usize>,
+    /// Width plus height past which the images are scaled on the GPU, when the server is built
+    /// with the `gpu` feature and there is one. Never when it's left out.
+    pub gpu_above: Option<u32>,
+    /// Widest and tallest source picture that is read, the others fail.
+    pub max_source_side: u32,
+    /// Megabytes reading a source picture can take, the ones that need more fail.
+    pub max_decode_mb: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_width: 6000,
+            max_height: 6000,
+            max_pixels: 12_250_000,
+            fast_filter_above: 3000,
+            generation_timeout: None,
+            max_generations: None,
+            generation_queue: None,
+            gpu_above: None,
+            max_source_side: 16384,
+            max_decode_mb: 512,
+        }
+    }
+}
+
+impl Limits {
+    /// Whether a `width` x `height` image is scaled on the GPU.
+    pub fn on_gpu(&self, width: u32, height: u32) -> bool {
+        self.gpu_above
+            .is_some_and(|above| width.saturating_add(height) > above)
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Cache {
+    /// Megabytes of images kept in memory, 0 turns the memory cache off.
+    pub memory_mb: Option<u64>,
+    /// Megabytes of images kept on the disk, no limit when it's left out.
+    pub disk_mb: Option<u64>,
+    /// Seconds the images are kept before being made again, forever when it's left out.
+    pub max_age: Option<u64>,
+    pub redis_url: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Server {
+    /// Threads answering the requests, one per CPU core by default.
+    pub workers: Option<usize>,
+    /// Threads making the images, one per physical CPU core by default.
+    pub resize_threads: Option<usize>,
+    /// Connections each worker takes at once, 25000 by default.
+    pub max_connections: Option<usize>,
+    /// Seconds an idle connection is kept open for the next request, 0 closes it. 5 by default.
+    pub keep_alive: Option<u64>,
+    /// Seconds a client has to send the headers of its request, 0 for no limit. 5 by default.
+    pub client_request_timeout: Option<u64>,
+    /// Seconds the requests going on when the server is stopped get to finish, 30 by default.
+    pub shutdown_timeout: Option<u64>,
+    /// Lets another server listen on the same port, to start the new version of the server before
+    /// stopping the old one.
+    pub reuse_port: bool,
+    /// Refuses to start when source pictures are missing or can't be read, instead of only
+    /// logging them.
+    pub strict_sources: bool,
+    /// `off`, `error`, `warn`, `info` to log every request, `debug` or `trace`.
+    pub log_level: Option<String>,
+}
+
+impl Config {
+    /// Reads the configuration file, the defaults when there is none.
+    pub fn load() -> io::Result<Self> {
+        let path = match env::var_os("PLACECAGE_CONFIG") {
+            Some(path) => PathBuf::from(path),
+            None if Path::new(DEFAULT_CONFIG_FILE).is_file() => PathBuf::from(DEFAULT_CONFIG_FILE),
+            None => return Ok(Config::default()),
+        };
+        let with_path = |e: &dyn std::fmt::Display| format!("{}: {e}", path.display());
+        let text =
+            fs::read_to_string(&path).map_err(|e| io::Error::new(e.kind(), with_path(&e)))?;
+        toml::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, with_path(&e)))
+    }
+}
@@ -0,0 +1,73 @@
+//! Letting browser apps on other origins fetch the images and the admin endpoints.
+
+use crate::ratelimit::{X_API_KEY, X_RATELIMIT_REMAINING};
+use crate::Settings;
+use actix_cors::Cors;
+use actix_web::http::header;
+use actix_web::http::{Method, Uri};
+use std::io;
+
+/// CORS for the origins in the settings, it only has an effect when there are some.
+pub fn cors(settings: &Settings) -> Cors {
+    let mut cors = Cors::default()
+        .allowed_methods(settings.cors_methods.clone())
+        // The admin endpoints take their token in it.
+        .allowed_header(header::AUTHORIZATION)
+        .allowed_header(X_API_KEY)
+        .expose_headers([
+            header::ETAG,
+            header::HeaderName::from_static("x-cache"),
+            header::RETRY_AFTER,
+            X_RATELIMIT_REMAINING,
+        ]);
+    for origin in &settings.cors_origins {
+        cors = match origin.as_str() {
+            "*" => cors.allow_any_origin(),
+            origin => cors.allowed_origin(origin),
+        };
+    }
+    cors
+}
+
+/// Parses a comma separated list of origins, like `https://example.com,http://localhost:3000`,
+/// or `*` for any of them.
+pub fn parse_origins(value: &str) -> io::Result<Vec<String>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|origin| !origin.is_empty())
+        .map(|origin| {
+            let valid = origin == "*"
+                || origin.parse::<Uri>().is_ok_and(|uri| {
+                    uri.scheme().is_some()
+                        && uri.host().is_some()
+                        && uri.path_and_query().is_none_or(|path| path == "/")
+                        && !origin.ends_with('/')
+                });
+            if valid {
+                Ok(origin.to_string())
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("`{origin}` is not an origin like https://example.com"),
+                ))
+            }
+        })
+        .collect()
+}
+
+/// Parses a comma separated list of HTTP methods, like `GET,HEAD`.
+pub fn parse_methods(value: &str) -> io::Result<Vec<Method>> {
+    value
+        .split(',')
+        .map(|method| {
+            let method = method.trim().to_uppercase();
+            method.parse().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("`{method}` is not an HTTP method"),
+                )
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,55 @@
+//! Reading the source pictures within limits, so a broken or malicious one can't take all the
+//! memory there is while it's decoded.
+
+use crate::config::Limits;
+use image::codecs::gif::GifDecoder;
+use image::io::Reader as ImageReader;
+use image::{ImageDecoder, ImageResult};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// Set once at startup, the image crate's own until then.
+static LIMITS: OnceLock<image::io::Limits> = OnceLock::new();
+
+/// Limits the pictures decoded from now on to the `limits`. Only the first call counts, an error
+/// when the memory limit is more than 64 bits can count.
+pub fn set_limits(limits: &Limits) -> io::Result<()> {
+    let mut decode = image::io::Limits::default();
+    decode.max_image_width = Some(limits.max_source_side);
+    decode.max_image_height = Some(limits.max_source_side);
+    let max_alloc = limits
+        .max_decode_mb
+        .checked_mul(1024 * 1024)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "max_decode_mb can't be over {} megabytes",
+                    u64::MAX / (1024 * 1024)
+                ),
+            )
+        })?;
+    decode.max_alloc = Some(max_alloc);
+    let _ = LIMITS.set(decode);
+    Ok(())
+}
+
+pub fn limits() -> image::io::Limits {
+    LIMITS.get().cloned().unwrap_or_default()
+}
+
+/// The picture at `path`, read as the format its content says it is whatever its extension.
+pub fn open(path: &Path) -> ImageResult<ImageReader<BufReader<File>>> {
+    let mut reader = ImageReader::open(path)?.with_guessed_format()?;
+    reader.limits(limits());
+    Ok(reader)
+}
+
+/// The frames of the gif at `path`.
+pub fn gif(path: &Path) -> ImageResult<GifDecoder<BufReader<File>>> {
+    let mut decoder = GifDecoder::new(BufReader::new(File::open(path)?))?;
+    decoder.set_limits(limits())?;
+    Ok(decoder)
+}
@@ -0,0 +1,463 @@
+//! Adjustments applied to an image once it has been resized.
+
+use crate::hash::StableHasher;
+use crate::text::{self, Captions, TextOverlay};
+use image::imageops::FilterType;
+use image::{DynamicImage, Rgba};
+use serde::{Deserialize, Deserializer};
+use std::fmt::Display;
+use std::io;
+use std::ops::RangeInclusive;
+
+/// Blurring gets slower the bigger the radius, and past this the image is just a smudge anyway.
+const MAX_BLUR: u32 = 50;
+const MAX_PIXELATE: u32 = 500;
+const MAX_BORDER: u32 = 500;
+/// Longer text doesn't fit on a single line at any readable size.
+const MAX_TEXT_LENGTH: usize = 100;
+/// Brightness and contrast are percentages of the full adjustment in either direction.
+const ADJUSTMENT_RANGE: RangeInclusive<i32> = -100..=100;
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Filter {
+    Sepia,
+    Invert,
+}
+
+impl std::fmt::Display for Filter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Filter::Sepia => write!(f, "sepia"),
+            Filter::Invert => write!(f, "invert"),
+        }
+    }
+}
+
+/// Clockwise quarter turns.
+#[derive(Deserialize, Clone, Copy)]
+pub enum Rotation {
+    #[serde(rename = "90")]
+    Rotate90,
+    #[serde(rename = "180")]
+    Rotate180,
+    #[serde(rename = "270")]
+    Rotate270,
+}
+
+impl Rotation {
+    /// Whether the width and height trade places.
+    pub fn swaps_sides(self) -> bool {
+        matches!(self, Rotation::Rotate90 | Rotation::Rotate270)
+    }
+}
+
+impl std::fmt::Display for Rotation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rotation::Rotate90 => write!(f, "90"),
+            Rotation::Rotate180 => write!(f, "180"),
+            Rotation::Rotate270 => write!(f, "270"),
+        }
+    }
+}
+
+/// Mirroring along the horizontal, vertical or both axes.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Flip {
+    H,
+    V,
+    Hv,
+}
+
+impl std::fmt::Display for Flip {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Flip::H => write!(f, "h"),
+            Flip::V => write!(f, "v"),
+            Flip::Hv => write!(f, "hv"),
+        }
+    }
+}
+
+/// Outline the image is cut to, leaving the rest transparent.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Shape {
+    /// Rounds the corners as far as they go, a circle on square images and a pill otherwise.
+    Circle,
+}
+
+impl std::fmt::Display for Shape {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Shape::Circle => write!(f, "circle"),
+        }
+    }
+}
+
+/// A `RRGGBB` hex color.
+#[derive(Clone, Copy)]
+pub struct Color(pub [u8; 3]);
+
+impl std::str::FromStr for Color {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.trim_start_matches('#');
+        let invalid = || format!("invalid color `{s}`, expected RRGGBB");
+        if hex.len() != 6 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid());
+        Ok(Color([channel(0)?, channel(2)?, channel(4)?]))
+    }
+}
+
+impl<'de> Deserialize<'de> for Color {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl std::fmt::Display for Color {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Color([r, g, b]) = self;
+        write!(f, "{r:02x}{g:02x}{b:02x}")
+    }
+}
+
+/// The two colors a duotone maps the darkest and lightest pixels to, written `dark,light`.
+#[derive(Clone, Copy)]
+pub struct Duotone {
+    dark: Color,
+    light: Color,
+}
+
+impl<'de> Deserialize<'de> for Duotone {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        let (dark, light) = value
+            .split_once(',')
+            .ok_or_else(|| serde::de::Error::custom("expected two colors, dark,light"))?;
+        Ok(Duotone {
+            dark: dark.parse().map_err(serde::de::Error::custom)?,
+            light: light.parse().map_err(serde::de::Error::custom)?,
+        })
+    }
+}
+
+/// A solid frame drawn inside the edges of the image, written `width,RRGGBB`.
+#[derive(Clone, Copy)]
+pub struct Border {
+    width: u32,
+    color: Color,
+}
+
+impl<'de> Deserialize<'de> for Border {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        let (width, color) = value.split_once(',').ok_or_else(|| {
+            serde::de::Error::custom("expected a width and a color, width,RRGGBB")
+        })?;
+        Ok(Border {
+            width: width.parse().map_err(serde::de::Error::custom)?,
+            color: color.parse().map_err(serde::de::Error::custom)?,
+        })
+    }
+}
+
+/// Every effect requested for an image, applied in the order they are declared.
+#[derive(Default)]
+pub struct Effects {
+    /// Applied to the source before it is resized, so the output keeps the requested size.
+    pub rotate: Option<Rotation>,
+    pub flip: Option<Flip>,
+    pub filter: Option<Filter>,
+    pub brightness: Option<i32>,
+    pub contrast: Option<i32>,
+    /// Degrees to rotate the hue by.
+    pub hue: Option<u32>,
+    /// Color multiplied into every pixel.
+    pub tint: Option<Color>,
+    pub duotone: Option<Duotone>,
+    /// Gaussian blur radius, handy for low quality image placeholders.
+    pub blur: Option<u32>,
+    /// Size in pixels of the mosaic blocks.
+    pub pixelate: Option<u32>,
+    /// Text written across the middle of the image.
+    pub text: Option<TextOverlay>,
+    pub captions: Option<Captions>,
+    pub border: Option<Border>,
+    /// Corner radius in pixels.
+    pub radius: Option<u32>,
+    pub shape: Option<Shape>,
+}
+
+impl Effects {
+    pub fn validate(&self) -> io::Result<()> {
+        check_range("blur", self.blur, 1..=MAX_BLUR)?;
+        check_range("pixelate", self.pixelate, 2..=MAX_PIXELATE)?;
+        check_range("brightness", self.brightness, ADJUSTMENT_RANGE)?;
+        check_range("contrast", self.contrast, ADJUSTMENT_RANGE)?;
+        check_range("hue", self.hue, 0..=360)?;
+        check_range(
+            "text length",
+            self.text
+                .as_ref()
+                .map(|overlay| overlay.text.chars().count()),
+            0..=MAX_TEXT_LENGTH,
+        )?;
+        if let Some(captions) = &self.captions {
+            for caption in [&captions.top, &captions.bottom] {
+                check_range(
+                    "caption length",
+                    caption.as_ref().map(|caption| caption.chars().count()),
+                    0..=MAX_TEXT_LENGTH,
+                )?;
+            }
+        }
+        check_range(
+            "border width",
+            self.border.map(|border| border.width),
+            1..=MAX_BORDER,
+        )
+    }
+
+    /// Whether the effects cut out parts of the image, which only formats with an alpha channel
+    /// can show.
+    pub fn needs_alpha(&self) -> bool {
+        self.radius.is_some() || self.shape.is_some()
+    }
+
+    /// The effects that change the shape of the image and have to run before resizing.
+    pub fn orient(&self, image: DynamicImage) -> DynamicImage {
+        let image = match self.rotate {
+            Some(Rotation::Rotate90) => image.rotate90(),
+            Some(Rotation::Rotate180) => image.rotate180(),
+            Some(Rotation::Rotate270) => image.rotate270(),
+            None => image,
+        };
+        match self.flip {
+            Some(Flip::H) => image.fliph(),
+            Some(Flip::V) => image.flipv(),
+            Some(Flip::Hv) => image.fliph().flipv(),
+            None => image,
+        }
+    }
+
+    /// The effects that run on the resized image.
+    pub fn apply(&self, image: DynamicImage) -> DynamicImage {
+        let mut image = match self.filter {
+            Some(Filter::Sepia) => sepia(image),
+            Some(Filter::Invert) => {
+                let mut image = image;
+                image.invert();
+                image
+            }
+            None => image,
+        };
+        if let Some(brightness) = self.brightness {
+            image = image.brighten(brightness * 255 / 100);
+        }
+        if let Some(contrast) = self.contrast {
+            image = image.adjust_contrast(contrast as f32);
+        }
+        if let Some(hue) = self.hue {
+            image = image.huerotate(hue as i32);
+        }
+        if let Some(color) = self.tint {
+            image = tint(image, color);
+        }
+        if let Some(colors) = self.duotone {
+            image = duotone(image, colors);
+        }
+        if let Some(blur) = self.blur {
+            image = image.blur(blur as f32);
+        }
+        if let Some(block_size) = self.pixelate {
+            image = pixelate(image, block_size);
+        }
+        if let Some(overlay) = &self.text {
+            let mut canvas = image.into_rgba8();
+            text::draw_centered(&mut canvas, &overlay.font, &overlay.text);
+            image = DynamicImage::ImageRgba8(canvas);
+        }
+        if let Some(captions) = &self.captions {
+            let mut canvas = image.into_rgba8();
+            text::draw_captions(&mut canvas, captions);
+            image = DynamicImage::ImageRgba8(canvas);
+        }
+        if let Some(frame) = self.border {
+            image = border(image, frame);
+        }
+        match (self.shape, self.radius) {
+            (Some(Shape::Circle), _) => image = round_corners(image, u32::MAX),
+            (None, Some(radius)) => image = round_corners(image, radius),
+            (None, None) => {}
+        }
+        image
+    }
+
+    /// Suffix appended to the cached file name so each combination of effects gets its own file.
+    pub fn cache_suffix(&self) -> String {
+        let mut suffix = String::new();
+        if let Some(rotate) = self.rotate {
+            suffix.push_str(&format!("_r{rotate}"));
+        }
+        if let Some(flip) = self.flip {
+            suffix.push_str(&format!("_flip{flip}"));
+        }
+        if let Some(filter) = self.filter {
+            suffix.push_str(&format!("_{filter}"));
+        }
+        if let Some(brightness) = self.brightness {
+            suffix.push_str(&format!("_b{brightness}"));
+        }
+        if let Some(contrast) = self.contrast {
+            suffix.push_str(&format!("_c{contrast}"));
+        }
+        if let Some(hue) = self.hue {
+            suffix.push_str(&format!("_hue{hue}"));
+        }
+        if let Some(tint) = self.tint {
+            suffix.push_str(&format!("_tint{tint}"));
+        }
+        if let Some(Duotone { dark, light }) = self.duotone {
+            suffix.push_str(&format!("_duo{dark}-{light}"));
+        }
+        if let Some(blur) = self.blur {
+            suffix.push_str(&format!("_blur{blur}"));
+        }
+        if let Some(pixelate) = self.pixelate {
+            suffix.push_str(&format!("_px{pixelate}"));
+        }
+        if let Some(overlay) = &self.text {
+            // The text can hold anything, including characters that aren't allowed in file names.
+            let hash = StableHasher::new()
+                .text(Some(&overlay.text))
+                .text(overlay.font_name.as_deref())
+                .finish();
+            suffix.push_str(&format!("_text{hash:016x}"));
+        }
+        if let Some(captions) = &self.captions {
+            let hash = StableHasher::new()
+                .text(captions.top.as_deref())
+                .text(captions.bottom.as_deref())
+                .text(captions.font_name.as_deref())
+                .finish();
+            suffix.push_str(&format!("_caption{hash:016x}"));
+        }
+        if let Some(Border { width, color }) = self.border {
+            suffix.push_str(&format!("_border{width}-{color}"));
+        }
+        if let Some(radius) = self.radius {
+            suffix.push_str(&format!("_radius{radius}"));
+        }
+        if let Some(shape) = self.shape {
+            suffix.push_str(&format!("_{shape}"));
+        }
+        suffix
+    }
+}
+
+fn check_range<T: PartialOrd + Display>(
+    name: &str,
+    value: Option<T>,
+    range: RangeInclusive<T>,
+) -> io::Result<()> {
+    match value {
+        Some(value) if !range.contains(&value) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{name} must be between {} and {}",
+                range.start(),
+                range.end()
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn sepia(image: DynamicImage) -> DynamicImage {
+    let mut image = image.into_rgba8();
+    for Rgba([r, g, b, _]) in image.pixels_mut() {
+        let (red, green, blue) = (f32::from(*r), f32::from(*g), f32::from(*b));
+        *r = (0.393 * red + 0.769 * green + 0.189 * blue).min(255.0) as u8;
+        *g = (0.349 * red + 0.686 * green + 0.168 * blue).min(255.0) as u8;
+        *b = (0.272 * red + 0.534 * green + 0.131 * blue).min(255.0) as u8;
+    }
+    DynamicImage::ImageRgba8(image)
+}
+
+fn tint(image: DynamicImage, Color(color): Color) -> DynamicImage {
+    let mut image = image.into_rgba8();
+    for Rgba(pixel) in image.pixels_mut() {
+        for (channel, tint) in pixel.iter_mut().zip(color) {
+            *channel = (u16::from(*channel) * u16::from(tint) / 255) as u8;
+        }
+    }
+    DynamicImage::ImageRgba8(image)
+}
+
+fn duotone(image: DynamicImage, Duotone { dark, light }: Duotone) -> DynamicImage {
+    let (Color(dark), Color(light)) = (dark, light);
+    let mut image = image.into_rgba8();
+    for Rgba([r, g, b, _]) in image.pixels_mut() {
+        let luma = (0.299 * f32::from(*r) + 0.587 * f32::from(*g) + 0.114 * f32::from(*b)) / 255.0;
+        let mix = |i: usize| {
+            (f32::from(dark[i]) + (f32::from(light[i]) - f32::from(dark[i])) * luma).round() as u8
+        };
+        (*r, *g, *b) = (mix(0), mix(1), mix(2));
+    }
+    DynamicImage::ImageRgba8(image)
+}
+
+/// Averages the image down to one pixel per block and scales it back up without smoothing.
+fn pixelate(image: DynamicImage, block_size: u32) -> DynamicImage {
+    let (width, height) = (image.width(), image.height());
+    image
+        .resize_exact(
+            (width / block_size).max(1),
+            (height / block_size).max(1),
+            FilterType::Triangle,
+        )
+        .resize_exact(width, height, FilterType::Nearest)
+}
+
+fn border(image: DynamicImage, Border { width, color }: Border) -> DynamicImage {
+    let Color([r, g, b]) = color;
+    let mut image = image.into_rgba8();
+    let (image_width, image_height) = image.dimensions();
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        let inside = x >= width
+            && y >= width
+            && x < image_width.saturating_sub(width)
+            && y < image_height.saturating_sub(width);
+        if !inside {
+            *pixel = Rgba([r, g, b, 255]);
+        }
+    }
+    DynamicImage::ImageRgba8(image)
+}
+
+/// Makes everything outside the rounded rectangle transparent, `radius` is clamped to half of the
+/// shortest side. Edge pixels are partially covered so the curve comes out smooth.
+fn round_corners(image: DynamicImage, radius: u32) -> DynamicImage {
+    let mut image = image.into_rgba8();
+    let (width, height) = (image.width() as f32, image.height() as f32);
+    let radius = (radius as f32).min(width.min(height) / 2.0);
+    for (x, y, Rgba([_, _, _, alpha])) in image.enumerate_pixels_mut() {
+        let (x, y) = (x as f32 + 0.5, y as f32 + 0.5);
+        let dx = (radius - x).max(x - (width - radius)).max(0.0);
+        let dy = (radius - y).max(y - (height - radius)).max(0.0);
+        if dx > 0.0 && dy > 0.0 {
+            let coverage = (radius - dx.hypot(dy) + 0.5).clamp(0.0, 1.0);
+            *alpha = (f32::from(*alpha) * coverage).round() as u8;
+        }
+    }
+    DynamicImage::ImageRgba8(image)
+}
@@ -0,0 +1,193 @@
+//! What can go wrong answering an image request, and the status each of them gets. The errors
+//! are answered as JSON, like `{"error": "dimensions_too_large", "message": "...", "max": 6000}`.
+
+use crate::text;
+use ab_glyph::FontArc;
+use actix_web::http::header::{self, Accept, Quality};
+use actix_web::http::StatusCode;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use image::{DynamicImage, ImageError, ImageFormat, Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::sync::Arc;
+
+/// Background of the error images, the usual placeholder gray.
+const ERROR_IMAGE_BACKGROUND: Rgba<u8> = Rgba([204, 204, 204, 255]);
+
+#[derive(Debug, thiserror::Error)]
+pub enum PlacecageError {
+    /// Something the request asks for that can't be made, like a quality over 100.
+    #[error("{0}")]
+    InvalidRequest(String),
+    #[error("the width can't be over {0} pixels")]
+    TooWide(u32),
+    #[error("the height can't be over {0} pixels")]
+    TooTall(u32),
+    #[error("an image can't have over {0} pixels")]
+    TooManyPixels(u64),
+    /// There is no source picture for the request, like a kind the subject has none of.
+    #[error("{0}")]
+    NotFound(String),
+    /// Too many images are being made, it may not be the case in a second.
+    #[error("too many images are being made, try again later")]
+    Busy,
+    /// The image took over the generation timeout, in seconds. It's still made and cached.
+    #[error("the image took over {0}s to make, try again later")]
+    Timeout(u64),
+    /// Reading the source picture or writing the image failed.
+    #[error("could not make the image: {0}")]
+    Image(#[from] ImageError),
+    /// The checks the other modules make come as io errors, the invalid input ones are the
+    /// request's fault.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// The error of an image another request was making, the requests waiting on it all get it.
+    #[error(transparent)]
+    Shared(Arc<PlacecageError>),
+}
+
+/// `?errors=`, how the errors are answered. Without it the requests asking for `image/*` but not
+/// for HTML, like the ones of an `<img>` tag, get an image and the others JSON.
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorFormat {
+    Json,
+    Image,
+}
+
+impl ErrorFormat {
+    /// What the `Accept` header of `req` says it takes.
+    pub fn accepted(req: &HttpRequest) -> Self {
+        let Some(accept) = req.get_header::<Accept>() else {
+            return ErrorFormat::Json;
+        };
+        let accepts = |mime: &str| {
+            accept
+                .iter()
+                .any(|item| item.item.essence_str() == mime && item.quality > Quality::ZERO)
+        };
+        if accepts("image/*") && !accepts("text/html") {
+            ErrorFormat::Image
+        } else {
+            ErrorFormat::Json
+        }
+    }
+}
+
+/// The body of an error response.
+#[derive(Serialize)]
+struct ErrorBody {
+    /// What went wrong, for the programs reading it.
+    error: &'static str,
+    /// What went wrong, for the people reading it.
+    message: String,
+    /// The limit the request went over.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after: Option<u64>,
+}
+
+impl PlacecageError {
+    /// The `error` of the JSON body, one per way the request can fail.
+    fn code(&self) -> &'static str {
+        match self {
+            PlacecageError::InvalidRequest(_) => "invalid_request",
+            PlacecageError::TooWide(_)
+            | PlacecageError::TooTall(_)
+            | PlacecageError::TooManyPixels(_) => "dimensions_too_large",
+            PlacecageError::NotFound(_) => "not_found",
+            PlacecageError::Busy => "busy",
+            PlacecageError::Timeout(_) => "timeout",
+            PlacecageError::Image(_) => "image_failed",
+            PlacecageError::Io(e) => match e.kind() {
+                io::ErrorKind::NotFound => "not_found",
+                io::ErrorKind::InvalidInput => "invalid_request",
+                _ => "internal",
+            },
+            PlacecageError::Shared(shared) => shared.code(),
+        }
+    }
+
+    fn max(&self) -> Option<u64> {
+        match self {
+            PlacecageError::TooWide(max) | PlacecageError::TooTall(max) => Some(u64::from(*max)),
+            PlacecageError::TooManyPixels(max) => Some(*max),
+            PlacecageError::Shared(shared) => shared.max(),
+            _ => None,
+        }
+    }
+
+    /// Answers the error with an image `encoded` in `format` instead of JSON, with the same
+    /// status.
+    pub fn image_response(&self, encoded: Vec<u8>, format: ImageFormat) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if let Some(seconds) = self.retry_after() {
+            response.insert_header((header::RETRY_AFTER, seconds.to_string()));
+        }
+        // The error may well be gone next time.
+        response
+            .insert_header((header::CACHE_CONTROL, "no-store"))
+            .content_type(format.to_mime_type())
+            .body(encoded)
+    }
+
+    /// Seconds to wait before asking again, for the errors that go away on their own.
+    fn retry_after(&self) -> Option<u64> {
+        match self {
+            PlacecageError::Busy => Some(1),
+            PlacecageError::Timeout(seconds) => Some(*seconds),
+            PlacecageError::Shared(shared) => shared.retry_after(),
+            _ => None,
+        }
+    }
+}
+
+/// The error of `status` and `message` written on a gray `width` x `height` image, so a page
+/// embedding the image shows why it's broken. Taken apart from the error so it can be drawn on
+/// another thread.
+pub fn draw(
+    status: StatusCode,
+    message: &str,
+    width: u32,
+    height: u32,
+    font: &FontArc,
+) -> DynamicImage {
+    let mut image = RgbaImage::from_pixel(width, height, ERROR_IMAGE_BACKGROUND);
+    text::draw_error(&mut image, font, &status.to_string(), message);
+    DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(image).into_rgb8())
+}
+
+impl ResponseError for PlacecageError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            PlacecageError::InvalidRequest(_)
+            | PlacecageError::TooWide(_)
+            | PlacecageError::TooTall(_)
+            | PlacecageError::TooManyPixels(_) => StatusCode::BAD_REQUEST,
+            PlacecageError::NotFound(_) => StatusCode::NOT_FOUND,
+            PlacecageError::Busy | PlacecageError::Timeout(_) => StatusCode::SERVICE_UNAVAILABLE,
+            PlacecageError::Image(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            PlacecageError::Io(e) => match e.kind() {
+                io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+                io::ErrorKind::InvalidInput => StatusCode::BAD_REQUEST,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            PlacecageError::Shared(shared) => shared.status_code(),
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        let retry_after = self.retry_after();
+        if let Some(seconds) = retry_after {
+            response.insert_header((header::RETRY_AFTER, seconds.to_string()));
+        }
+        response.json(ErrorBody {
+            error: self.code(),
+            message: self.to_string(),
+            max: self.max(),
+            retry_after,
+        })
+    }
+}
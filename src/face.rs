@@ -0,0 +1,46 @@
+//! Face detection for the face aware crop.
+
+use image::imageops::FilterType;
+use image::DynamicImage;
+use rustface::{ImageData, Model};
+use std::sync::OnceLock;
+
+static MODEL_DATA: &[u8] = include_bytes!("../assets/models/seeta_fd_frontal_v1.0.bin");
+
+/// Faces are searched on a copy at most this big, detection time grows quickly with the size and
+/// the faces in the sources are big enough to be found at it.
+const DETECTION_SIZE: u32 = 512;
+/// The detector doesn't look for anything smaller than this.
+const MIN_FACE_SIZE: u32 = 20;
+
+fn model() -> &'static Model {
+    static MODEL: OnceLock<Model> = OnceLock::new();
+    MODEL.get_or_init(|| rustface::read_model(MODEL_DATA).expect("the embedded model is valid"))
+}
+
+/// Center of the biggest face in `image`, in its pixel coordinates.
+pub fn find_face(image: &DynamicImage) -> Option<(f64, f64)> {
+    let gray = image
+        .resize(DETECTION_SIZE, DETECTION_SIZE, FilterType::Triangle)
+        .into_luma8();
+    if gray.width() < MIN_FACE_SIZE || gray.height() < MIN_FACE_SIZE {
+        return None;
+    }
+    let ratio = f64::from(image.width()) / f64::from(gray.width());
+
+    let mut detector = rustface::create_detector_with_model(model().clone());
+    detector.set_min_face_size(MIN_FACE_SIZE);
+    detector.set_score_thresh(2.0);
+    detector.set_pyramid_scale_factor(0.8);
+    detector.set_slide_window_step(4, 4);
+
+    let faces = detector.detect(&ImageData::new(gray.as_raw(), gray.width(), gray.height()));
+    let face = faces
+        .iter()
+        .map(|face| face.bbox())
+        .max_by_key(|bbox| bbox.width() * bbox.height())?;
+    Some((
+        (f64::from(face.x()) + f64::from(face.width()) / 2.0) * ratio,
+        (f64::from(face.y()) + f64::from(face.height()) / 2.0) * ratio,
+    ))
+}
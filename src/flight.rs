@@ -0,0 +1,139 @@
+//! Making each image once when several requests ask for it at the same time, and only so many
+//! images at once.
+
+use crate::cache::Cached;
+use crate::error::PlacecageError;
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+
+/// The images being made, by cache key.
+static IN_FLIGHT: Mutex<BTreeMap<String, Arc<Flight>>> = Mutex::new(BTreeMap::new());
+/// How many images can be made at once, no limit when it isn't set.
+static LIMIT: OnceLock<Limit> = OnceLock::new();
+
+/// The images being made and waiting to be, out of the most there can be.
+struct Limit {
+    max: usize,
+    /// Requests past it are turned away, no limit when `None`.
+    queue: Option<usize>,
+    counts: Mutex<Counts>,
+    freed: Condvar,
+}
+
+#[derive(Default)]
+struct Counts {
+    making: usize,
+    waiting: usize,
+}
+
+/// Makes at most `max` images at once, the next ones wait their turn. Past `queue` of them
+/// waiting the others fail with [`PlacecageError::Busy`]. Only the first call counts.
+pub fn limit(max: usize, queue: Option<usize>) {
+    let _ = LIMIT.set(Limit {
+        max,
+        queue,
+        counts: Mutex::default(),
+        freed: Condvar::new(),
+    });
+}
+
+/// A turn at making an image, the next one gets it when it's dropped.
+struct Turn;
+
+fn take_turn() -> Result<Option<Turn>, PlacecageError> {
+    let Some(limit) = LIMIT.get() else {
+        return Ok(None);
+    };
+    let mut counts = limit.counts.lock().unwrap();
+    if counts.making >= limit.max {
+        if limit.queue.is_some_and(|queue| counts.waiting >= queue) {
+            return Err(PlacecageError::Busy);
+        }
+        counts.waiting += 1;
+        while counts.making >= limit.max {
+            counts = limit.freed.wait(counts).unwrap();
+        }
+        counts.waiting -= 1;
+    }
+    counts.making += 1;
+    Ok(Some(Turn))
+}
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        // Only handed out when there is a limit.
+        let limit = LIMIT.get().unwrap();
+        limit.counts.lock().unwrap().making -= 1;
+        limit.freed.notify_one();
+    }
+}
+
+/// An image being made, the requests waiting on it share its error.
+#[derive(Default)]
+struct Flight {
+    result: Mutex<Option<Result<Cached, Arc<PlacecageError>>>>,
+    done: Condvar,
+}
+
+/// Runs `make` for the image under `key` once it gets its turn, unless it's already being made,
+/// then waits for that instead and gets the same result.
+pub fn run(
+    key: &str,
+    make: impl FnOnce() -> Result<Cached, PlacecageError>,
+) -> Result<Cached, PlacecageError> {
+    let (flight, leading) = {
+        let mut in_flight = IN_FLIGHT.lock().unwrap();
+        match in_flight.get(key) {
+            Some(flight) => (Arc::clone(flight), false),
+            None => {
+                let flight = Arc::new(Flight::default());
+                in_flight.insert(key.to_string(), Arc::clone(&flight));
+                (flight, true)
+            }
+        }
+    };
+
+    if !leading {
+        let mut result = flight.result.lock().unwrap();
+        while result.is_none() {
+            result = flight.done.wait(result).unwrap();
+        }
+        return shared(result.as_ref().unwrap());
+    }
+
+    let landing = Landing { key, flight };
+    // Turned away, the requests waiting on it are too.
+    let result = take_turn().and_then(|_turn| make()).map_err(Arc::new);
+    let shared = shared(&result);
+    *landing.flight.result.lock().unwrap() = Some(result);
+    shared
+}
+
+fn shared(result: &Result<Cached, Arc<PlacecageError>>) -> Result<Cached, PlacecageError> {
+    match result {
+        Ok(cached) => Ok(cached.clone()),
+        Err(e) => Err(PlacecageError::Shared(Arc::clone(e))),
+    }
+}
+
+/// Ends the flight even when making the image panicked, so the requests waiting on it don't wait
+/// forever.
+struct Landing<'a> {
+    key: &'a str,
+    flight: Arc<Flight>,
+}
+
+impl Drop for Landing<'_> {
+    fn drop(&mut self) {
+        // The image is in the caches by now, the next requests find it there.
+        IN_FLIGHT.lock().unwrap().remove(self.key);
+        let mut result = self.flight.result.lock().unwrap();
+        if result.is_none() {
+            *result = Some(Err(Arc::new(PlacecageError::Io(io::Error::other(
+                "the image could not be made",
+            )))));
+        }
+        self.flight.done.notify_all();
+    }
+}
@@ -0,0 +1,246 @@
+//! Scaling the biggest images on the GPU, with bilinear sampling. The GPU is looked for the first
+//! time an image needs it, without one the images are scaled on the CPU like the others.
+
+use image::{DynamicImage, RgbaImage};
+use std::sync::{mpsc, OnceLock};
+
+/// The GPU and what's set up on it once, `None` when there is no usable one.
+static GPU: OnceLock<Option<Gpu>> = OnceLock::new();
+
+/// Pixels of the output computed by each workgroup, along each side.
+const WORKGROUP_SIZE: u32 = 8;
+
+const SHADER: &str = r"
+struct Params {
+    // The output width and height, the other two are padding.
+    size: vec4<u32>,
+    // The left, top, width and height of the source sampled, as fractions of its size.
+    crop: vec4<f32>,
+}
+
+@group(0) @binding(0) var source: texture_2d<f32>;
+@group(0) @binding(1) var bilinear: sampler;
+@group(0) @binding(2) var<uniform> params: Params;
+@group(0) @binding(3) var<storage, read_write> output: array<u32>;
+
+@compute @workgroup_size(8, 8)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    if id.x >= params.size.x || id.y >= params.size.y {
+        return;
+    }
+    let position = (vec2<f32>(id.xy) + 0.5) / vec2<f32>(params.size.xy);
+    let uv = params.crop.xy + position * params.crop.zw;
+    output[id.y * params.size.x + id.x] = pack4x8unorm(textureSampleLevel(source, bilinear, uv, 0.0));
+}
+";
+
+struct Gpu {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    pipeline: wgpu::ComputePipeline,
+    sampler: wgpu::Sampler,
+}
+
+fn gpu() -> Option<&'static Gpu> {
+    GPU.get_or_init(|| {
+        let gpu = pollster::block_on(connect());
+        match &gpu {
+            Some(_) => log::info!("scaling the biggest images on the GPU"),
+            None => log::warn!("no GPU to scale the images on, they're scaled on the CPU"),
+        }
+        gpu
+    })
+    .as_ref()
+}
+
+async fn connect() -> Option<Gpu> {
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+    let adapter = instance
+        .request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            ..Default::default()
+        })
+        .await?;
+    // A GPU emulated on the CPU, like llvmpipe, is slower than scaling on the CPU directly.
+    if adapter.get_info().device_type == wgpu::DeviceType::Cpu {
+        return None;
+    }
+    let (device, queue) = adapter
+        .request_device(
+            &wgpu::DeviceDescriptor {
+                label: Some("placecage"),
+                required_features: wgpu::Features::empty(),
+                // As big as the adapter goes, the images can be.
+                required_limits: adapter.limits(),
+            },
+            None,
+        )
+        .await
+        .map_err(|e| log::warn!("could not open the GPU: {e}"))
+        .ok()?;
+    let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("resample"),
+        source: wgpu::ShaderSource::Wgsl(SHADER.into()),
+    });
+    let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+        label: Some("resample"),
+        layout: None,
+        module: &shader,
+        entry_point: "main",
+    });
+    let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+        mag_filter: wgpu::FilterMode::Linear,
+        min_filter: wgpu::FilterMode::Linear,
+        ..Default::default()
+    });
+    Some(Gpu {
+        device,
+        queue,
+        pipeline,
+        sampler,
+    })
+}
+
+/// The `crop` of `image`, as its left, top, width and height, or the whole of it, scaled to
+/// `width` x `height`. `None` when there is no GPU or the images are too big for it.
+pub fn resample(
+    image: &DynamicImage,
+    crop: Option<(f64, f64, f64, f64)>,
+    width: u32,
+    height: u32,
+) -> Option<DynamicImage> {
+    let gpu = gpu()?;
+    let limits = gpu.device.limits();
+    let output_size = u64::from(width) * u64::from(height) * 4;
+    let fits = |side: u32| side > 0 && side <= limits.max_texture_dimension_2d;
+    if !fits(image.width())
+        || !fits(image.height())
+        || width == 0
+        || height == 0
+        || output_size > u64::from(limits.max_storage_buffer_binding_size)
+        || width.div_ceil(WORKGROUP_SIZE) > limits.max_compute_workgroups_per_dimension
+        || height.div_ceil(WORKGROUP_SIZE) > limits.max_compute_workgroups_per_dimension
+    {
+        return None;
+    }
+
+    let source = image.to_rgba8();
+    let size = wgpu::Extent3d {
+        width: source.width(),
+        height: source.height(),
+        depth_or_array_layers: 1,
+    };
+    let texture = gpu.device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("source"),
+        size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba8Unorm,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    });
+    gpu.queue.write_texture(
+        texture.as_image_copy(),
+        &source,
+        wgpu::ImageDataLayout {
+            offset: 0,
+            bytes_per_row: Some(4 * source.width()),
+            rows_per_image: Some(source.height()),
+        },
+        size,
+    );
+
+    let (source_width, source_height) = (f64::from(image.width()), f64::from(image.height()));
+    let (left, top, crop_width, crop_height) =
+        crop.unwrap_or((0.0, 0.0, source_width, source_height));
+    let mut params = Vec::with_capacity(32);
+    for value in [width, height, 0, 0] {
+        params.extend_from_slice(&value.to_ne_bytes());
+    }
+    for value in [
+        left / source_width,
+        top / source_height,
+        crop_width / source_width,
+        crop_height / source_height,
+    ] {
+        params.extend_from_slice(&(value as f32).to_ne_bytes());
+    }
+    let params_buffer = gpu.device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("params"),
+        size: params.len() as u64,
+        usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+    gpu.queue.write_buffer(&params_buffer, 0, &params);
+    let output = gpu.device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("output"),
+        size: output_size,
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        mapped_at_creation: false,
+    });
+    let readback = gpu.device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("readback"),
+        size: output_size,
+        usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    let bind_group = gpu.device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: None,
+        layout: &gpu.pipeline.get_bind_group_layout(0),
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(&gpu.sampler),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: params_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: output.as_entire_binding(),
+            },
+        ],
+    });
+    let mut encoder = gpu
+        .device
+        .create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+    {
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor::default());
+        pass.set_pipeline(&gpu.pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.dispatch_workgroups(
+            width.div_ceil(WORKGROUP_SIZE),
+            height.div_ceil(WORKGROUP_SIZE),
+            1,
+        );
+    }
+    encoder.copy_buffer_to_buffer(&output, 0, &readback, 0, output_size);
+    gpu.queue.submit([encoder.finish()]);
+
+    let slice = readback.slice(..);
+    let (sender, mapped) = mpsc::channel();
+    slice.map_async(wgpu::MapMode::Read, move |result| {
+        let _ = sender.send(result);
+    });
+    gpu.device.poll(wgpu::Maintain::Wait);
+    if let Err(e) = mapped.recv().ok()? {
+        log::warn!("could not read the image back from the GPU: {e}");
+        return None;
+    }
+    let pixels = slice.get_mapped_range().to_vec();
+    readback.unmap();
+    let scaled = DynamicImage::ImageRgba8(RgbaImage::from_raw(width, height, pixels)?);
+    Some(if image.color().has_alpha() {
+        scaled
+    } else {
+        DynamicImage::ImageRgb8(scaled.into_rgb8())
+    })
+}
@@ -0,0 +1,78 @@
+//! A hash that stays the same between Rust versions, for what outlives the process: the names of
+//! the cached files, the ETags and the pictures the seeds pick. The std hasher is free to change,
+//! which would make an upgrade lose the cache and every client's ETags, and give the seeds other
+//! pictures.
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// 64 bits FNV-1a.
+pub struct StableHasher(u64);
+
+impl StableHasher {
+    pub fn new() -> Self {
+        StableHasher(FNV_OFFSET)
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) -> &mut Self {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(FNV_PRIME);
+        }
+        self
+    }
+
+    /// Adds a text field, marked so that `None` and `Some("")` differ and the text can't run
+    /// into the next field.
+    pub fn text(&mut self, text: Option<&str>) -> &mut Self {
+        match text {
+            Some(text) => self.write(&[1]).write(text.as_bytes()).write(&[0xff]),
+            None => self.write(&[0]),
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// The hash of `bytes`.
+pub fn stable_hash(bytes: &[u8]) -> u64 {
+    StableHasher::new().write(bytes).finish()
+}
+
+/// 32 bits FNV-1a of `bytes`, for picking among a few sources.
+pub fn stable_hash32(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, &byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_fnv_1a_reference_values() {
+        assert_eq!(stable_hash(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(stable_hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(stable_hash(b"foobar"), 0x8594_4171_f739_67e8);
+        assert_eq!(stable_hash32(b""), 0x811c_9dc5);
+        assert_eq!(stable_hash32(b"a"), 0xe40c_292c);
+        assert_eq!(stable_hash32(b"foobar"), 0xbf9c_f968);
+    }
+
+    #[test]
+    fn text_fields_do_not_run_into_each_other() {
+        let split = |first, second| {
+            StableHasher::new()
+                .text(Some(first))
+                .text(Some(second))
+                .finish()
+        };
+        assert_ne!(split("ab", "c"), split("a", "bc"));
+        assert_ne!(
+            StableHasher::new().text(None).finish(),
+            StableHasher::new().text(Some("")).finish()
+        );
+    }
+}
@@ -0,0 +1,50 @@
+//! Keeping other sites from embedding the images, by the `Referer` of the requests.
+
+use actix_web::http::header;
+use actix_web::http::Uri;
+use actix_web::HttpRequest;
+use std::io;
+
+/// Parses a comma separated list of domains, like `example.com,blog.example.org`.
+pub fn parse_domains(value: &str) -> io::Result<Vec<String>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|domain| !domain.is_empty())
+        .map(|domain| {
+            let valid = domain.parse::<Uri>().is_ok_and(|uri| {
+                uri.host() == Some(domain) && uri.scheme().is_none() && uri.port().is_none()
+            });
+            if valid {
+                Ok(domain.to_ascii_lowercase())
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("`{domain}` is not a domain like example.com"),
+                ))
+            }
+        })
+        .collect()
+}
+
+/// Whether `req` comes from one of the `domains` or their subdomains. Requests without a
+/// `Referer` are let through, they are the images opened on their own and the browsers that
+/// don't send it.
+pub fn is_allowed(req: &HttpRequest, domains: &[String]) -> bool {
+    let Some(referer) = req.headers().get(header::REFERER) else {
+        return true;
+    };
+    let host = referer
+        .to_str()
+        .ok()
+        .and_then(|referer| referer.parse::<Uri>().ok())
+        .and_then(|uri| uri.host().map(str::to_ascii_lowercase));
+    host.is_some_and(|host| {
+        domains.iter().any(|domain| {
+            host == *domain
+                || host
+                    .strip_suffix(domain.as_str())
+                    .is_some_and(|subdomain| subdomain.ends_with('.'))
+        })
+    })
+}
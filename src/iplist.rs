@@ -0,0 +1,69 @@
+//! Lists of client addresses the server answers, or doesn't.
+
+use std::io;
+use std::net::IpAddr;
+
+/// A range of addresses, written like `10.0.0.0/8` or `fd00::/8`. A single address is a range of
+/// one.
+#[derive(Clone, Copy)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // An IPv4 client on a dual stack socket shows up as `::ffff:a.b.c.d`.
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => same_prefix(
+                u32::from(network).into(),
+                u32::from(ip).into(),
+                self.prefix,
+                32,
+            ),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                same_prefix(network.into(), ip.into(), self.prefix, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether the first `prefix` of the `bits` bits of `a` and `b` are the same.
+fn same_prefix(a: u128, b: u128, prefix: u32, bits: u32) -> bool {
+    let host_bits = bits - prefix;
+    host_bits >= 128 || (a >> host_bits) == (b >> host_bits)
+}
+
+/// Whether `ip` is in any of the `ranges`.
+pub fn contains(ranges: &[Cidr], ip: IpAddr) -> bool {
+    ranges.iter().any(|range| range.contains(ip))
+}
+
+/// Parses a comma separated list of address ranges, like `10.0.0.0/8,192.168.1.7,fd00::/8`.
+pub fn parse_cidrs(value: &str) -> io::Result<Vec<Cidr>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|range| !range.is_empty())
+        .map(|range| {
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("`{range}` is not an address range like 10.0.0.0/8"),
+                )
+            };
+            let (network, prefix) = range.split_once('/').unwrap_or((range, ""));
+            let network: IpAddr = network.parse().map_err(|_| invalid())?;
+            let bits = if network.is_ipv4() { 32 } else { 128 };
+            let prefix = match prefix {
+                "" => bits,
+                prefix => prefix.parse().map_err(|_| invalid())?,
+            };
+            if prefix > bits {
+                return Err(invalid());
+            }
+            Ok(Cidr { network, prefix })
+        })
+        .collect()
+}
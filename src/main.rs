@@ -1,14 +1,77 @@
-use actix_files::NamedFile;
-use actix_web::HttpRequest;
-use actix_web::{get, web, App, HttpServer};
-use image::io::Reader as ImageReader;
-use image::{DynamicImage, ImageError};
-use serde::Deserialize;
+mod admin;
+mod animation;
+mod cache;
+mod cli;
+mod config;
+mod cors;
+mod decode;
+mod effects;
+mod error;
+#[cfg(feature = "face")]
+mod face;
+mod flight;
+#[cfg(feature = "gpu")]
+mod gpu;
+mod hash;
+mod hotlink;
+mod iplist;
+mod pool;
+mod ratelimit;
+mod resize;
+mod selection;
+mod signing;
+mod sources;
+#[cfg(unix)]
+mod systemd;
+mod text;
+#[cfg(feature = "tls")]
+mod tls;
+mod warm;
+
+use actix_web::body::{self, BoxBody, MessageBody};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, Accept, CacheDirective, EntityTag, IfNoneMatch, Quality};
+use actix_web::http::uri::{PathAndQuery, Uri};
+use actix_web::http::{KeepAlive, Method, StatusCode};
+use actix_web::middleware::{Condition, DefaultHeaders, Logger};
+use actix_web::web::Bytes;
+use actix_web::{routes, web, App, HttpServer};
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use cache::{CacheBackend, CacheConfig, CacheKind, Cached};
+use clap::Parser;
+use cli::{Cli, Command, ServeArgs};
+use config::{Config, Limits};
+use effects::{Border, Color, Duotone, Effects, Filter, Flip, Rotation, Shape};
+use error::{ErrorFormat, PlacecageError};
+#[cfg(feature = "avif")]
+use image::codecs::avif::AvifEncoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::{WebPEncoder, WebPQuality};
+use image::error::{EncodingError, ImageFormatHint, LimitError, LimitErrorKind};
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageError, ImageFormat};
+use iplist::Cidr;
+use ratelimit::{Quotas, RateLimiter};
+use resize::{Crop, Fit, Gravity, Resample, Resize};
+use selection::{Exclude, Selection, Weights};
+use serde::de::value::StrDeserializer;
+use serde::{Deserialize, Deserializer};
+use socket2::{Domain, Protocol, Socket, Type};
+use sources::Sources;
+use std::collections::BTreeMap;
 use std::fs;
+use std::io::{Cursor, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
 use std::str;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{env, io};
+use text::{Captions, Fonts, TextOverlay};
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone, Copy)]
 #[serde(rename_all = "snake_case")]
 enum Subject {
     Cage,
@@ -16,6 +79,10 @@ enum Subject {
     Segall,
 }
 
+impl Subject {
+    const ALL: [Subject; 3] = [Subject::Cage, Subject::Murray, Subject::Segall];
+}
+
 impl std::fmt::Display for Subject {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -26,7 +93,7 @@ impl std::fmt::Display for Subject {
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone, Copy, PartialEq)]
 #[serde(rename_all = "snake_case")]
 enum ImageKind {
     Default,
@@ -34,6 +101,17 @@ enum ImageKind {
     Gif,
 }
 
+impl ImageKind {
+    const ALL: [ImageKind; 3] = [ImageKind::Default, ImageKind::Crazy, ImageKind::Gif];
+
+    fn source_extension(&self) -> &'static str {
+        match self {
+            ImageKind::Gif => "gif",
+            _ => "jpg",
+        }
+    }
+}
+
 impl std::fmt::Display for ImageKind {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -44,81 +122,1725 @@ impl std::fmt::Display for ImageKind {
     }
 }
 
-fn get_image_count(subject: Subject, kind: ImageKind) -> u32 {
-    match (subject, kind) {
-        (Subject::Cage, ImageKind::Crazy) => 23,
-        (Subject::Cage, ImageKind::Default) => 33,
-        (Subject::Cage, ImageKind::Gif) => 43,
-        (Subject::Murray, ImageKind::Default) => 23,
-        (Subject::Segall, ImageKind::Default) => 30,
-        _ => 0,
+/// Path prefixes the original placecage.com used for the cage variations.
+#[derive(Deserialize)]
+enum ShortKind {
+    #[serde(rename = "c")]
+    Crazy,
+    #[serde(rename = "gif")]
+    Gif,
+}
+
+impl From<ShortKind> for ImageKind {
+    fn from(short_kind: ShortKind) -> Self {
+        match short_kind {
+            ShortKind::Crazy => ImageKind::Crazy,
+            ShortKind::Gif => ImageKind::Gif,
+        }
     }
 }
 
-fn resize_to_fill(
-    width: u32,
-    height: u32,
-    input_path: &str,
-    output_path: &str,
-) -> Result<DynamicImage, ImageError> {
-    let image = ImageReader::open(input_path)?.decode()?;
-    let filter = if (width + height) > 3000 {
-        image::imageops::FilterType::Nearest
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum OutputFormat {
+    #[serde(alias = "jpg")]
+    Jpeg,
+    Png,
+    Webp,
+    #[cfg(feature = "avif")]
+    Avif,
+    Gif,
+}
+
+impl std::fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputFormat::Jpeg => write!(f, "jpg"),
+            OutputFormat::Png => write!(f, "png"),
+            OutputFormat::Webp => write!(f, "webp"),
+            #[cfg(feature = "avif")]
+            OutputFormat::Avif => write!(f, "avif"),
+            OutputFormat::Gif => write!(f, "gif"),
+        }
+    }
+}
+
+impl From<OutputFormat> for ImageFormat {
+    fn from(format: OutputFormat) -> Self {
+        match format {
+            OutputFormat::Jpeg => ImageFormat::Jpeg,
+            OutputFormat::Png => ImageFormat::Png,
+            OutputFormat::Webp => ImageFormat::WebP,
+            #[cfg(feature = "avif")]
+            OutputFormat::Avif => ImageFormat::Avif,
+            OutputFormat::Gif => ImageFormat::Gif,
+        }
+    }
+}
+
+impl OutputFormat {
+    fn supports_alpha(&self) -> bool {
+        !matches!(self, OutputFormat::Jpeg)
+    }
+
+    fn supports_animation(&self) -> bool {
+        matches!(self, OutputFormat::Gif | OutputFormat::Webp)
+    }
+}
+
+/// Converts the image to a pixel layout the encoder accepts, only keeping the alpha channel when
+/// the output format is able to store it.
+fn prepare_for_encoding(image: DynamicImage, format: OutputFormat) -> DynamicImage {
+    if format.supports_alpha() && image.color().has_alpha() {
+        DynamicImage::ImageRgba8(image.into_rgba8())
     } else {
-        image::imageops::FilterType::CatmullRom
+        DynamicImage::ImageRgb8(image.into_rgb8())
+    }
+}
+
+/// How a resized image gets written to disk.
+#[derive(Clone, Copy)]
+struct Encoding {
+    format: OutputFormat,
+    /// Quality for the lossy encoders, `None` keeps each encoder's own default.
+    quality: Option<u8>,
+    /// Write JPEGs as progressive scans instead of a single baseline scan.
+    progressive: bool,
+    /// Resolution of the colors in JPEGs, the encoder's own default when `None`.
+    chroma: Option<Chroma>,
+    #[cfg_attr(not(feature = "mozjpeg"), allow(dead_code))]
+    jpeg_backend: JpegBackend,
+}
+
+impl Encoding {
+    /// Suffix appended to the cached file name so differently encoded copies don't collide.
+    fn cache_suffix(&self) -> String {
+        let mut suffix = String::new();
+        if let Some(quality) = self.quality {
+            suffix.push_str(&format!("_q{quality}"));
+        }
+        if self.progressive {
+            suffix.push_str("_p");
+        }
+        if let Some(chroma) = self.chroma {
+            suffix.push_str(&format!("_{chroma}"));
+        }
+        #[cfg(feature = "mozjpeg")]
+        if matches!(self.jpeg_backend, JpegBackend::Mozjpeg) {
+            suffix.push_str("_moz");
+        }
+        suffix
+    }
+}
+
+/// Chroma subsampling of the JPEGs, how many pixels share their color.
+#[derive(Clone, Copy)]
+enum Chroma {
+    /// Every pixel keeps its own color, for the sharp edges of print.
+    Full,
+    /// Each 2x2 block shares a color, the eye hardly sees it and the file is smaller.
+    Quarter,
+}
+
+impl std::fmt::Display for Chroma {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Chroma::Full => write!(f, "444"),
+            Chroma::Quarter => write!(f, "420"),
+        }
+    }
+}
+
+/// `?preset=`, the resampling filter, quality and chroma subsampling picked together for what the
+/// image is for. `?filter=` and `?q=` still win over it.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum Preset {
+    /// Small and fast to make.
+    Thumb,
+    Web,
+    /// As sharp as it gets, whatever the file size.
+    Print,
+}
+
+impl Preset {
+    fn resample(self) -> Resample {
+        match self {
+            Preset::Thumb => Resample::Triangle,
+            Preset::Web => Resample::CatmullRom,
+            Preset::Print => Resample::Lanczos3,
+        }
+    }
+
+    fn quality(self) -> u8 {
+        match self {
+            Preset::Thumb => 70,
+            Preset::Web => 80,
+            Preset::Print => 95,
+        }
+    }
+
+    fn chroma(self) -> Chroma {
+        match self {
+            Preset::Thumb | Preset::Web => Chroma::Quarter,
+            Preset::Print => Chroma::Full,
+        }
+    }
+}
+
+/// Library used to encode JPEG output.
+#[derive(Clone, Copy)]
+enum JpegBackend {
+    Image,
+    /// Produces noticeably smaller files than the `image` encoder at the same quality.
+    #[cfg(feature = "mozjpeg")]
+    Mozjpeg,
+}
+
+impl str::FromStr for JpegBackend {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "image" => Ok(JpegBackend::Image),
+            #[cfg(feature = "mozjpeg")]
+            "mozjpeg" => Ok(JpegBackend::Mozjpeg),
+            #[cfg(not(feature = "mozjpeg"))]
+            "mozjpeg" => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "mozjpeg support requires building with the `mozjpeg` feature",
+            )),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown JPEG encoder `{s}`"),
+            )),
+        }
+    }
+}
+
+/// Server-wide settings, read once at startup.
+#[derive(Clone)]
+struct Settings {
+    jpeg_backend: JpegBackend,
+    limits: Limits,
+    /// Threads answering the requests, one per CPU core when `None`.
+    workers: Option<usize>,
+    /// Threads making the images, one per physical CPU core when `None`.
+    resize_threads: Option<usize>,
+    /// The connection limits, the actix defaults when `None`.
+    max_connections: Option<usize>,
+    keep_alive: Option<Duration>,
+    client_request_timeout: Option<Duration>,
+    shutdown_timeout: Option<Duration>,
+    log_level: log::LevelFilter,
+    fonts: Fonts,
+    /// Shown instead of the drawn 404 errors, decoded once at startup.
+    not_found_image: Option<Arc<DynamicImage>>,
+    sources: Sources,
+    /// How often each source is picked, from the weights files next to them.
+    weights: Weights,
+    cache: CacheConfig,
+    /// Bearer token for the admin endpoints, they are off without one.
+    admin_token: Option<String>,
+    /// Sizes made for every subject and kind at startup.
+    warm_sizes: Vec<(u32, u32)>,
+    /// Sent with the images, they only change when their source does so they can be kept for a
+    /// while.
+    cache_control: header::CacheControl,
+    /// Origins browser apps can fetch from, `*` for any of them. CORS is off when there are none.
+    cors_origins: Vec<String>,
+    cors_methods: Vec<Method>,
+    /// Added to every response, for the pages the images are embedded in.
+    security_headers: Vec<(header::HeaderName, header::HeaderValue)>,
+    /// How many requests each client can make, there is no limit without it.
+    rate_limiter: Option<Arc<RateLimiter>>,
+    /// Whether the client address is taken from the headers of a proxy in front of the server.
+    trust_proxy: bool,
+    /// The API keys the requests need and their daily quotas, no key is needed without them.
+    quotas: Option<Arc<Quotas>>,
+    /// Key the image URLs are signed with, they don't need a signature without it.
+    signing_key: Option<Vec<u8>>,
+    /// Sites the images can be embedded in, any of them when there are none.
+    hotlink_domains: Vec<String>,
+    /// Where the requests from other sites are sent, instead of turning them down.
+    hotlink_redirect: Option<Uri>,
+    /// Addresses of the only clients answered, all of them when there are none.
+    allowed_ips: Vec<Cidr>,
+    /// Addresses of the clients turned down, even when they are allowed.
+    denied_ips: Vec<Cidr>,
+    /// Addresses to listen on.
+    bind: Vec<SocketAddr>,
+    /// Path of the unix socket to listen on instead of TCP.
+    unix_socket: Option<PathBuf>,
+    /// Whether the TCP sockets are bound with `SO_REUSEPORT`.
+    reuse_port: bool,
+    /// Whether the server refuses to start with problems in the sources, it only logs them
+    /// otherwise.
+    strict_sources: bool,
+    /// Certificate chain and private key to serve HTTPS with.
+    #[cfg(feature = "tls")]
+    tls: Option<(PathBuf, PathBuf)>,
+}
+
+impl Settings {
+    /// Reads the configuration file and the environment variables, which win over it.
+    fn load() -> io::Result<Self> {
+        let config = Config::load()?;
+        let gpu_above = env_size("PLACECAGE_GPU_ABOVE")?.or(config.limits.gpu_above);
+        #[cfg(not(feature = "gpu"))]
+        if gpu_above.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "scaling on the GPU requires building with the `gpu` feature",
+            ));
+        }
+        let limits = Limits {
+            max_width: env_size("PLACECAGE_MAX_WIDTH")?.unwrap_or(config.limits.max_width),
+            max_height: env_size("PLACECAGE_MAX_HEIGHT")?.unwrap_or(config.limits.max_height),
+            max_pixels: env_number("PLACECAGE_MAX_PIXELS", "pixels")?
+                .unwrap_or(config.limits.max_pixels),
+            fast_filter_above: env_size("PLACECAGE_FAST_FILTER_ABOVE")?
+                .unwrap_or(config.limits.fast_filter_above),
+            generation_timeout: env_number("PLACECAGE_GENERATION_TIMEOUT", "seconds")?
+                .or(config.limits.generation_timeout)
+                .filter(|&seconds| seconds > 0),
+            max_generations: env_number("PLACECAGE_MAX_GENERATIONS", "images")?
+                .map(|max| max as usize)
+                .or(config.limits.max_generations)
+                .filter(|&max| max > 0),
+            generation_queue: env_number("PLACECAGE_GENERATION_QUEUE", "images")?
+                .map(|queue| queue as usize)
+                .or(config.limits.generation_queue),
+            gpu_above,
+            max_source_side: env_size("PLACECAGE_MAX_SOURCE_SIDE")?
+                .unwrap_or(config.limits.max_source_side),
+            max_decode_mb: env_number("PLACECAGE_MAX_DECODE_MB", "megabytes")?
+                .unwrap_or(config.limits.max_decode_mb),
+        };
+        // Before any picture is read, the not found one included.
+        decode::set_limits(&limits)?;
+        let jpeg_backend = match env::var("PLACECAGE_JPEG_ENCODER") {
+            Ok(value) => value.parse()?,
+            Err(_) => JpegBackend::Image,
+        };
+        let fonts_dir = env::var_os("PLACECAGE_FONTS_DIR")
+            .map(PathBuf::from)
+            .or(config.paths.fonts);
+        let fonts = Fonts::load(fonts_dir.as_deref())?;
+        let not_found_image = env::var_os("PLACECAGE_NOT_FOUND_IMAGE")
+            .map(PathBuf::from)
+            .or(config.paths.not_found_image)
+            .map(|path| -> io::Result<Arc<DynamicImage>> {
+                let image = decode::open(&path)
+                    .and_then(|reader| reader.decode())
+                    .map_err(|e| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("{}: {e}", path.display()),
+                        )
+                    })?;
+                Ok(Arc::new(image))
+            })
+            .transpose()?;
+        let sources_dir = env::current_dir()?
+            .join(env::var_os("PLACECAGE_SOURCES_DIR").map_or(config.paths.sources, PathBuf::from));
+        let weights = Weights::load(&sources_dir)?;
+        let subjects = env_parse("PLACECAGE_SUBJECTS", warm::parse_subjects)?
+            .or(config.subjects)
+            .unwrap_or_else(|| Subject::ALL.to_vec());
+        let sources = Sources::load(sources_dir, subjects)?;
+        let memory_cache_size = env_megabytes("PLACECAGE_MEMORY_CACHE_MB")?
+            .or(config
+                .cache
+                .memory_mb
+                .map(|megabytes| megabytes_to_bytes("memory_mb", megabytes))
+                .transpose()?)
+            .unwrap_or(DEFAULT_MEMORY_CACHE_MB * 1024 * 1024);
+        let redis_url = env::var("PLACECAGE_REDIS_URL")
+            .ok()
+            .or(config.cache.redis_url);
+        let diskless = env_flag("PLACECAGE_DISKLESS")?.unwrap_or(false);
+        let caches = match env::var("PLACECAGE_CACHE").as_deref() {
+            Ok("none") => Vec::new(),
+            Ok(value) => value
+                .split(',')
+                .map(|kind| kind.trim().parse())
+                .collect::<io::Result<Vec<_>>>()?,
+            // Memory first as it's the fastest, the disk next as it outlives restarts and Redis
+            // last since it's across the network.
+            Err(_) => [
+                (memory_cache_size > 0).then_some(CacheKind::Memory),
+                (!diskless).then_some(CacheKind::Fs),
+                redis_url.is_some().then_some(CacheKind::Redis),
+            ]
+            .into_iter()
+            .flatten()
+            .collect(),
+        };
+        if diskless && caches.contains(&CacheKind::Fs) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "PLACECAGE_DISKLESS can't be used with the fs cache",
+            ));
+        }
+        let mut cache_control = vec![
+            if env_flag("PLACECAGE_CACHE_CONTROL_PRIVATE")?.unwrap_or(false) {
+                CacheDirective::Private
+            } else {
+                CacheDirective::Public
+            },
+            CacheDirective::MaxAge(
+                env_number("PLACECAGE_CACHE_CONTROL_MAX_AGE", "seconds")?
+                    .map_or(DEFAULT_CACHE_CONTROL_MAX_AGE, |max_age| {
+                        u32::try_from(max_age).unwrap_or(u32::MAX)
+                    }),
+            ),
+        ];
+        if env_flag("PLACECAGE_CACHE_CONTROL_IMMUTABLE")?.unwrap_or(false) {
+            cache_control.push(CacheDirective::Extension("immutable".to_string(), None));
+        }
+        let burst = env_number("PLACECAGE_RATE_LIMIT_BURST", "requests")?;
+        let rate_limiter = env_number("PLACECAGE_RATE_LIMIT", "requests per minute")?
+            .filter(|&per_minute| per_minute > 0)
+            .map(|per_minute| {
+                Arc::new(RateLimiter::new(
+                    per_minute as f64 / 60.0,
+                    burst.unwrap_or(DEFAULT_RATE_LIMIT_BURST).max(1) as f64,
+                ))
+            });
+        let tls = match (
+            env::var_os("PLACECAGE_TLS_CERT"),
+            env::var_os("PLACECAGE_TLS_KEY"),
+        ) {
+            (Some(cert), Some(key)) => Some((PathBuf::from(cert), PathBuf::from(key))),
+            (None, None) => None,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "PLACECAGE_TLS_CERT and PLACECAGE_TLS_KEY have to be set together",
+                ))
+            }
+        };
+        let unix_socket = env::var_os("PLACECAGE_UNIX_SOCKET").map(PathBuf::from);
+        if unix_socket.is_some() && tls.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "PLACECAGE_UNIX_SOCKET can't be used with TLS, the proxy in front serves it",
+            ));
+        }
+        let reuse_port = env_flag("PLACECAGE_REUSE_PORT")?.unwrap_or(config.server.reuse_port);
+        #[cfg(not(unix))]
+        if reuse_port {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "PLACECAGE_REUSE_PORT is only supported on unix",
+            ));
+        }
+        #[cfg(not(unix))]
+        if unix_socket.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unix sockets are only supported on unix",
+            ));
+        }
+        #[cfg(not(feature = "tls"))]
+        if tls.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "TLS support requires building with the `tls` feature",
+            ));
+        }
+        Ok(Settings {
+            jpeg_backend,
+            limits,
+            workers: env_number("PLACECAGE_WORKERS", "threads")?
+                .map(|workers| workers as usize)
+                .or(config.server.workers)
+                .filter(|&workers| workers > 0),
+            resize_threads: env_number("PLACECAGE_RESIZE_THREADS", "threads")?
+                .map(|threads| threads as usize)
+                .or(config.server.resize_threads)
+                .filter(|&threads| threads > 0),
+            max_connections: env_number("PLACECAGE_MAX_CONNECTIONS", "connections")?
+                .map(|connections| connections as usize)
+                .or(config.server.max_connections),
+            keep_alive: env_number("PLACECAGE_KEEP_ALIVE", "seconds")?
+                .or(config.server.keep_alive)
+                .map(Duration::from_secs),
+            client_request_timeout: env_number("PLACECAGE_CLIENT_REQUEST_TIMEOUT", "seconds")?
+                .or(config.server.client_request_timeout)
+                .map(Duration::from_secs),
+            shutdown_timeout: env_number("PLACECAGE_SHUTDOWN_TIMEOUT", "seconds")?
+                .or(config.server.shutdown_timeout)
+                .map(Duration::from_secs),
+            log_level: match env_parse("PLACECAGE_LOG_LEVEL", parse_log_level)? {
+                Some(level) => level,
+                None => config
+                    .server
+                    .log_level
+                    .as_deref()
+                    .map_or(Ok(log::LevelFilter::Warn), parse_log_level)?,
+            },
+            fonts,
+            not_found_image,
+            sources,
+            weights,
+            cache: CacheConfig {
+                kinds: caches,
+                memory_size: memory_cache_size,
+                dir: env::current_dir()?.join(
+                    env::var_os("PLACECAGE_GENERATED_DIR")
+                        .map_or(config.paths.generated, PathBuf::from),
+                ),
+                disk_quota: env_megabytes("PLACECAGE_DISK_CACHE_MB")?.or(config
+                    .cache
+                    .disk_mb
+                    .map(|megabytes| megabytes_to_bytes("disk_mb", megabytes))
+                    .transpose()?),
+                max_age: env_number("PLACECAGE_CACHE_MAX_AGE", "seconds")?
+                    .or(config.cache.max_age)
+                    .map(Duration::from_secs),
+                redis_url,
+            },
+            admin_token: env::var("PLACECAGE_ADMIN_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty()),
+            warm_sizes: env_parse("PLACECAGE_WARM_SIZES", warm::parse_sizes)?.unwrap_or_default(),
+            cache_control: header::CacheControl(cache_control),
+            cors_origins: env_parse("PLACECAGE_CORS_ORIGINS", cors::parse_origins)?
+                .unwrap_or_default(),
+            cors_methods: env_parse("PLACECAGE_CORS_METHODS", cors::parse_methods)?
+                .unwrap_or_else(|| vec![Method::GET, Method::HEAD]),
+            security_headers: response_headers(&config.headers)?,
+            rate_limiter,
+            trust_proxy: env_flag("PLACECAGE_TRUST_PROXY")?.unwrap_or(false),
+            quotas: env_parse("PLACECAGE_API_KEYS", ratelimit::parse_api_keys)?
+                .filter(|quotas| !quotas.is_empty())
+                .map(|quotas| Arc::new(Quotas::new(quotas))),
+            signing_key: env::var("PLACECAGE_SIGNING_KEY")
+                .ok()
+                .filter(|key| !key.is_empty())
+                .map(String::into_bytes),
+            hotlink_domains: env_parse("PLACECAGE_HOTLINK_DOMAINS", hotlink::parse_domains)?
+                .unwrap_or_default(),
+            hotlink_redirect: env_parse("PLACECAGE_HOTLINK_REDIRECT", |value| {
+                value.parse().map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("`{value}` is not a URL"),
+                    )
+                })
+            })?,
+            allowed_ips: env_parse("PLACECAGE_ALLOWED_IPS", iplist::parse_cidrs)?
+                .unwrap_or_default(),
+            denied_ips: env_parse("PLACECAGE_DENIED_IPS", iplist::parse_cidrs)?.unwrap_or_default(),
+            bind: env_parse("PLACECAGE_BIND_ADDR", parse_addresses)?
+                .unwrap_or_else(|| vec![SocketAddr::from((Ipv4Addr::LOCALHOST, DEFAULT_PORT))]),
+            unix_socket,
+            reuse_port,
+            strict_sources: env_flag("PLACECAGE_STRICT_SOURCES")?
+                .unwrap_or(config.server.strict_sources),
+            #[cfg(feature = "tls")]
+            tls,
+        })
+    }
+}
+
+/// Reads an on/off environment variable, `None` when it isn't set.
+fn env_flag(name: &str) -> io::Result<Option<bool>> {
+    match env::var(name).as_deref() {
+        Ok("1" | "true") => Ok(Some(true)),
+        Ok("0" | "false") => Ok(Some(false)),
+        Err(_) => Ok(None),
+        Ok(other) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{name} must be 1, 0, true or false, not `{other}`"),
+        )),
+    }
+}
+
+/// Reads an environment variable with `parse`, the errors tell which variable it was.
+fn env_parse<T>(name: &str, parse: impl FnOnce(&str) -> io::Result<T>) -> io::Result<Option<T>> {
+    match env::var(name) {
+        Ok(value) => parse(&value)
+            .map(Some)
+            .map_err(|e| io::Error::new(e.kind(), format!("{name}: {e}"))),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Parses a comma separated list of addresses to listen on, like `0.0.0.0:8080,[::]:8080`.
+fn parse_addresses(value: &str) -> io::Result<Vec<SocketAddr>> {
+    let addresses = value
+        .split(',')
+        .map(str::trim)
+        .filter(|address| !address.is_empty())
+        .map(|address| {
+            address.parse().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("`{address}` is not an address like 0.0.0.0:8080 or [::]:8080"),
+                )
+            })
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    if addresses.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "there has to be an address to listen on",
+        ));
+    }
+    Ok(addresses)
+}
+
+/// Headers added to every response: the security headers, then the ones of the configuration
+/// file over them and their environment variables over both. The headers set empty are left out.
+fn response_headers(
+    configured: &BTreeMap<String, String>,
+) -> io::Result<Vec<(header::HeaderName, header::HeaderValue)>> {
+    let mut headers = vec![
+        (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+        // Nothing but images comes from here, an image opened on its own can't run anything
+        // either.
+        (
+            header::CONTENT_SECURITY_POLICY,
+            "default-src 'none'".to_string(),
+        ),
+        (header::REFERRER_POLICY, "no-referrer".to_string()),
+    ];
+    let mut set = |name: header::HeaderName, value: String| match headers
+        .iter_mut()
+        .find(|(existing, _)| *existing == name)
+    {
+        Some((_, existing)) => *existing = value,
+        None => headers.push((name, value)),
     };
-    let image_resized = image.resize_to_fill(width, height, filter);
-    image_resized.save(output_path)?;
-    Ok(image_resized)
+    for (name, value) in configured {
+        let name = header::HeaderName::try_from(name.as_str()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("`{name}` is not a valid header name"),
+            )
+        })?;
+        set(name, value.clone());
+    }
+    for (variable, name) in [
+        (
+            "PLACECAGE_CONTENT_TYPE_OPTIONS",
+            header::X_CONTENT_TYPE_OPTIONS,
+        ),
+        (
+            "PLACECAGE_CONTENT_SECURITY_POLICY",
+            header::CONTENT_SECURITY_POLICY,
+        ),
+        ("PLACECAGE_REFERRER_POLICY", header::REFERRER_POLICY),
+    ] {
+        if let Ok(value) = env::var(variable) {
+            set(name, value);
+        }
+    }
+    headers
+        .into_iter()
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(name, value)| {
+            let value = header::HeaderValue::try_from(&value).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("`{value}` is not a valid value for {name}"),
+                )
+            })?;
+            Ok((name, value))
+        })
+        .collect()
 }
 
-fn resize_to_fill_io(
-    width: u32,
-    height: u32,
-    input_path: &str,
-    output_path: &str,
-) -> io::Result<DynamicImage> {
-    match resize_to_fill(width, height, input_path, output_path) {
-        Err(image_error) => match image_error {
-            ImageError::Decoding(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
-            ImageError::Encoding(e) => return Err(io::Error::new(io::ErrorKind::Unsupported, e)),
-            ImageError::IoError(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
-            ImageError::Limits(e) => return Err(io::Error::new(io::ErrorKind::Unsupported, e)),
-            ImageError::Parameter(e) => return Err(io::Error::new(io::ErrorKind::InvalidInput, e)),
-            ImageError::Unsupported(e) => {
-                return Err(io::Error::new(io::ErrorKind::Unsupported, e))
+/// Reads an environment variable holding a number of `unit`s.
+fn env_number(name: &str, unit: &str) -> io::Result<Option<u64>> {
+    match env::var(name) {
+        Ok(value) => value.parse().map(Some).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{name} must be a number of {unit}, not `{value}`"),
+            )
+        }),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Reads an environment variable giving a size in pixels.
+fn env_size(name: &str) -> io::Result<Option<u32>> {
+    Ok(env_number(name, "pixels")?.map(|size| u32::try_from(size).unwrap_or(u32::MAX)))
+}
+
+/// Parses a log level, from `off` to `trace`.
+fn parse_log_level(value: &str) -> io::Result<log::LevelFilter> {
+    value.parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "`{value}` is not a log level, expected off, error, warn, info, debug or trace"
+            ),
+        )
+    })
+}
+
+/// Reads an environment variable giving a size in megabytes, as bytes.
+fn env_megabytes(name: &str) -> io::Result<Option<u64>> {
+    env_number(name, "megabytes")?
+        .map(|megabytes| megabytes_to_bytes(name, megabytes))
+        .transpose()
+}
+
+/// `megabytes` of the `setting` as bytes, an error when there are more than 64 bits can count.
+fn megabytes_to_bytes(setting: &str, megabytes: u64) -> io::Result<u64> {
+    megabytes.checked_mul(1024 * 1024).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{setting} can't be over {} megabytes",
+                u64::MAX / (1024 * 1024)
+            ),
+        )
+    })
+}
+
+/// Port listened on when none is given.
+const DEFAULT_PORT: u16 = 8080;
+/// Connections waiting to be accepted on each TCP socket, as many as actix lets wait.
+const TCP_BACKLOG: i32 = 2048;
+
+/// Requests a client can make at once before the rate limit kicks in.
+const DEFAULT_RATE_LIMIT_BURST: u64 = 20;
+
+/// A day, placeholders are usually looked at over and over during a work day.
+const DEFAULT_CACHE_CONTROL_MAX_AGE: u32 = 24 * 60 * 60;
+
+/// Enough to keep a few hundred of the usual placeholder sizes.
+const DEFAULT_MEMORY_CACHE_MB: u64 = 64;
+
+/// Same default quality the `image` crate uses for its baseline JPEG encoder.
+const DEFAULT_JPEG_QUALITY: u8 = 75;
+
+/// Writes the JPEG with `jpeg-encoder`, which unlike the `image` encoder can write progressive
+/// scans and pick the chroma subsampling.
+fn save_jpeg(
+    image: &DynamicImage,
+    writer: impl Write,
+    quality: u8,
+    progressive: bool,
+    chroma: Option<Chroma>,
+) -> Result<(), ImageError> {
+    let image = image.to_rgb8();
+    let mut encoder = jpeg_encoder::Encoder::new(writer, quality);
+    encoder.set_progressive(progressive);
+    match chroma {
+        Some(Chroma::Full) => encoder.set_sampling_factor(jpeg_encoder::SamplingFactor::R_4_4_4),
+        Some(Chroma::Quarter) => encoder.set_sampling_factor(jpeg_encoder::SamplingFactor::R_4_2_0),
+        None => {}
+    }
+    // Turned down before by the requests, a side over 16 bits would be cut short in the header.
+    let side = |side: u32| {
+        u16::try_from(side)
+            .map_err(|_| ImageError::Limits(LimitError::from_kind(LimitErrorKind::DimensionError)))
+    };
+    encoder
+        .encode(
+            image.as_raw(),
+            side(image.width())?,
+            side(image.height())?,
+            jpeg_encoder::ColorType::Rgb,
+        )
+        .map_err(|e| {
+            ImageError::Encoding(EncodingError::new(
+                ImageFormatHint::Exact(ImageFormat::Jpeg),
+                e,
+            ))
+        })
+}
+
+/// mozjpeg always writes progressive scans, so `Encoding::progressive` makes no difference here.
+#[cfg(feature = "mozjpeg")]
+fn save_mozjpeg(
+    image: &DynamicImage,
+    writer: impl Write,
+    quality: u8,
+    chroma: Option<Chroma>,
+) -> Result<(), ImageError> {
+    let image = image.to_rgb8();
+    // libjpeg reports errors by unwinding, so they have to be caught here.
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| -> io::Result<()> {
+        let mut compress = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+        compress.set_size(image.width() as usize, image.height() as usize);
+        compress.set_quality(f32::from(quality));
+        if let Some(chroma) = chroma {
+            // The horizontal and vertical pixels sharing a color, for both chroma channels.
+            let pixels = match chroma {
+                Chroma::Full => (1, 1),
+                Chroma::Quarter => (2, 2),
+            };
+            compress.set_chroma_sampling_pixel_sizes(pixels, pixels);
+        }
+        let mut compress = compress.start_compress(writer)?;
+        compress.write_scanlines(image.as_raw())?;
+        compress.finish()?;
+        Ok(())
+    }))
+    .map_err(|_| {
+        ImageError::Encoding(EncodingError::new(
+            ImageFormatHint::Exact(ImageFormat::Jpeg),
+            "mozjpeg failed to encode the image",
+        ))
+    })?
+    .map_err(ImageError::IoError)
+}
+
+fn encode_image(image: &DynamicImage, encoding: Encoding) -> Result<Vec<u8>, ImageError> {
+    let mut writer = Cursor::new(Vec::new());
+    match (encoding.format, encoding.quality) {
+        #[cfg(feature = "mozjpeg")]
+        (OutputFormat::Jpeg, quality) if matches!(encoding.jpeg_backend, JpegBackend::Mozjpeg) => {
+            save_mozjpeg(
+                image,
+                &mut writer,
+                quality.unwrap_or(DEFAULT_JPEG_QUALITY),
+                encoding.chroma,
+            )?
+        }
+        (OutputFormat::Jpeg, quality) if encoding.progressive || encoding.chroma.is_some() => {
+            save_jpeg(
+                image,
+                &mut writer,
+                quality.unwrap_or(DEFAULT_JPEG_QUALITY),
+                encoding.progressive,
+                encoding.chroma,
+            )?
+        }
+        (OutputFormat::Jpeg, Some(quality)) => {
+            image.write_with_encoder(JpegEncoder::new_with_quality(&mut writer, quality))?
+        }
+        (OutputFormat::Webp, Some(quality)) => image.write_with_encoder(
+            WebPEncoder::new_with_quality(&mut writer, WebPQuality::lossy(quality)),
+        )?,
+        #[cfg(feature = "avif")]
+        (OutputFormat::Avif, Some(quality)) => image
+            .write_with_encoder(AvifEncoder::new_with_speed_quality(&mut writer, 4, quality))?,
+        (format, _) => image.write_to(&mut writer, ImageFormat::from(format))?,
+    }
+    Ok(writer.into_inner())
+}
+
+/// The filter used when the request doesn't pick one, a fast one past `fast_filter_above`.
+fn resize_filter(width: u32, height: u32, limits: Limits) -> FilterType {
+    if width.saturating_add(height) > limits.fast_filter_above {
+        FilterType::Nearest
+    } else {
+        FilterType::CatmullRom
+    }
+}
+
+fn resize_image(
+    input_path: &Path,
+    resize: Resize,
+    encoding: Encoding,
+    grayscale: bool,
+    effects: &Effects,
+) -> Result<Vec<u8>, ImageError> {
+    // The sources are read by their content, a png named .jpg is still read.
+    let image = effects.orient(decode::open(input_path)?.decode()?);
+    let mut image_resized = resize.apply(&image, resize.filter);
+    if grayscale {
+        image_resized = image_resized.grayscale();
+    }
+    let image_resized = prepare_for_encoding(effects.apply(image_resized), encoding.format);
+    encode_image(&image_resized, encoding)
+}
+
+/// What the route path asked for, with anything left out of the URL as `None`.
+struct ImageRequest {
+    /// The sides left out follow the aspect ratio of the source, or its size when both are.
+    width: Option<u32>,
+    height: Option<u32>,
+    subject: Option<Subject>,
+    kind: Option<ImageKind>,
+    format: Option<OutputFormat>,
+    /// Only the first frame of an animated source.
+    poster: bool,
+    grayscale: bool,
+    /// Meme captions, from the query of the caption route.
+    captions: Option<GetCaptionQuery>,
+    /// Picks the source instead of the size, from the seed route.
+    seed: Option<String>,
+}
+
+/// The source image picked for a request.
+struct Source {
+    path: PathBuf,
+    /// The kind asked for, or the default one when the subject has none of it.
+    kind: ImageKind,
+    /// Always part of the cached file name, the picture a size picks changes with the sources
+    /// there are.
+    number: u32,
+    /// When the file was last modified, in seconds since the epoch, so the images made from it
+    /// are made again once it's replaced.
+    version: u64,
+}
+
+/// Picks the source image for the request, by default the same size always gets the same picture.
+fn select_source(
+    image: &ImageRequest,
+    selection: &Selection,
+    sources: &Sources,
+    weights: &Weights,
+) -> Result<Source, PlacecageError> {
+    let subject = image.subject.unwrap_or(Subject::Cage);
+    let mut kind = image.kind.unwrap_or(ImageKind::Default);
+    // The sources are listed in memory, so the combinations there are none of, like
+    // `murray/crazy`, get the default pictures of the subject without going to the disk.
+    let mut numbers = sources.numbers(subject, kind);
+    if numbers.is_empty() && kind != ImageKind::Default {
+        log::debug!("there are no {kind} pictures of {subject}, using the default ones");
+        kind = ImageKind::Default;
+        numbers = sources.numbers(subject, kind);
+    }
+    if numbers.is_empty() {
+        return Err(PlacecageError::NotFound(format!(
+            "there are no {kind} pictures of {subject}"
+        )));
+    }
+
+    // A side left out of the URL isn't known before the source is picked, so it can't count.
+    let key = image
+        .width
+        .unwrap_or(0)
+        .saturating_add(image.height.unwrap_or(0));
+    let weights = weights.get(&subject.to_string(), &kind.to_string());
+    let selected_image = selection.pick(&numbers, key, &weights)?;
+    let path = sources.path(subject, kind, selected_image);
+    let modified = fs::metadata(&path)?.modified()?;
+    Ok(Source {
+        path,
+        kind,
+        number: selected_image,
+        // A source dated before the epoch is as good as any other, it just has to stay the same.
+        version: modified
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs()),
+    })
+}
+
+/// The size the image comes out at, filling in the sides left out of the request from the source
+/// as it ends up after being rotated.
+fn output_size(
+    image: &ImageRequest,
+    source: &Path,
+    rotate: Option<Rotation>,
+) -> Result<(u32, u32), PlacecageError> {
+    if let (Some(width), Some(height)) = (image.width, image.height) {
+        return Ok((width, height));
+    }
+    let (mut source_width, mut source_height) =
+        decode::open(source).and_then(|reader| reader.into_dimensions())?;
+    if rotate.is_some_and(Rotation::swaps_sides) {
+        (source_width, source_height) = (source_height, source_width);
+    }
+    let scale = |side: u32, from: u32, to: u32| {
+        (f64::from(side) * f64::from(to) / f64::from(from))
+            .round()
+            .max(1.0) as u32
+    };
+    Ok(match (image.width, image.height) {
+        (Some(width), _) => (width, scale(width, source_width, source_height)),
+        (None, Some(height)) => (scale(height, source_height, source_width), height),
+        (None, None) => (source_width, source_height),
+    })
+}
+
+/// Checks the sides known of an image are within the `limits`, the ones left out to be computed
+/// are checked once they are.
+fn check_size(
+    width: Option<u32>,
+    height: Option<u32>,
+    limits: Limits,
+) -> Result<(), PlacecageError> {
+    if width == Some(0) || height == Some(0) {
+        return Err(PlacecageError::InvalidRequest(
+            "width and height must be at least 1".to_string(),
+        ));
+    }
+    if width.is_some_and(|width| width > limits.max_width) {
+        return Err(PlacecageError::TooWide(limits.max_width));
+    }
+    if height.is_some_and(|height| height > limits.max_height) {
+        return Err(PlacecageError::TooTall(limits.max_height));
+    }
+    if let (Some(width), Some(height)) = (width, height) {
+        if u64::from(width) * u64::from(height) > limits.max_pixels {
+            return Err(PlacecageError::TooManyPixels(limits.max_pixels));
+        }
+    }
+    Ok(())
+}
+
+/// Checks the request can be made and names the image it makes, the path of the image in the
+/// generated images directory.
+fn cache_key(
+    image: &ImageRequest,
+    density: f64,
+    source: &Source,
+    resize: Resize,
+    effects: &Effects,
+    encoding: Encoding,
+    limits: Limits,
+) -> Result<String, PlacecageError> {
+    check_size(Some(resize.width), Some(resize.height), limits)?;
+    if let Some(quality) = encoding.quality {
+        if !(1..=100).contains(&quality) {
+            return Err(PlacecageError::InvalidRequest(
+                "quality must be between 1 and 100".to_string(),
+            ));
+        }
+    }
+    resize.validate()?;
+    effects.validate()?;
+    if effects.needs_alpha() && !encoding.format.supports_alpha() {
+        return Err(PlacecageError::InvalidRequest(format!(
+            "{} can't store the transparency radius and shape need",
+            encoding.format
+        )));
+    }
+
+    let subject = image.subject.unwrap_or(Subject::Cage);
+    let kind = source.kind;
+    let format = encoding.format;
+
+    let mut dir = format!("{subject}/{kind}/{format}/");
+    if image.grayscale {
+        dir.push_str("gray/");
+    }
+    let mut suffix = format!("_img{}", source.number);
+    suffix += &(resize.cache_suffix() + &effects.cache_suffix() + &encoding.cache_suffix());
+    if image.poster {
+        suffix.push_str("_poster");
+    }
+    suffix.push_str(&format!("_v{}", source.version));
+    // The computed sides are named `auto`, the source for them is picked differently than for
+    // the same size written out in full.
+    let side = |requested: Option<u32>| requested.map_or("auto".to_string(), |s| s.to_string());
+    let mut size = format!("{}x{}", side(image.width), side(image.height));
+    if density != 1.0 {
+        size.push_str(&format!("@{density}x"));
+    }
+    Ok(format!("{dir}{size}{suffix}.{format}"))
+}
+
+/// The encoded image under `cache_key`, straight from memory when it was recently made. Requests
+/// arriving while it's being made wait for it instead of making it again.
+fn get_image(
+    image: &ImageRequest,
+    source: &Source,
+    resize: Resize,
+    effects: &Effects,
+    encoding: Encoding,
+    cache_key: &str,
+    cache: &dyn CacheBackend,
+) -> Result<Cached, PlacecageError> {
+    if let Some(cached) = cache.get(cache_key) {
+        return Ok(cached);
+    }
+
+    let kind = source.kind;
+    let format = encoding.format;
+    flight::run(cache_key, || {
+        let encoded = pool::run(|| {
+            if kind == ImageKind::Gif && format.supports_animation() && !image.poster {
+                animation::resize_animation(&source.path, resize, effects, encoding)
+            } else {
+                resize_image(&source.path, resize, encoding, image.grayscale, effects)
+            }
+        })?;
+
+        let cached = Cached {
+            bytes: Bytes::from(encoded),
+            made: SystemTime::now(),
+            found_in: None,
+        };
+        cache.insert(cache_key, &cached)?;
+        Ok(cached)
+    })
+}
+
+/// Picks the best output format the client advertises in its `Accept` header, falling back to
+/// JPEG which every client understands, or PNG when the image needs to keep its transparency.
+fn negotiate_format(req: &HttpRequest, needs_alpha: bool) -> OutputFormat {
+    let fallback = if needs_alpha {
+        OutputFormat::Png
+    } else {
+        OutputFormat::Jpeg
+    };
+    let accept = match req.get_header::<Accept>() {
+        Some(accept) => accept,
+        None => return fallback,
+    };
+    let accepts = |mime: &str| {
+        accept
+            .iter()
+            .any(|item| item.item.essence_str() == mime && item.quality > Quality::ZERO)
+    };
+
+    #[cfg(feature = "avif")]
+    if accepts("image/avif") {
+        return OutputFormat::Avif;
+    }
+    if accepts("image/webp") {
+        return OutputFormat::Webp;
+    }
+    fallback
+}
+
+/// The image, or the error drawn on an image of its size when the request is for one.
+async fn serve_image(
+    req: &HttpRequest,
+    settings: &web::Data<Settings>,
+    cache: &Arc<dyn CacheBackend>,
+    image: ImageRequest,
+    query: GetImageQuery,
+) -> Result<HttpResponse, PlacecageError> {
+    let errors = query.errors.unwrap_or_else(|| ErrorFormat::accepted(req));
+    let (width, height) = (image.width, image.height);
+    match serve_made_image(req, settings, cache, image, query).await {
+        Err(e) if errors == ErrorFormat::Image => {
+            Ok(error_image(&e, width, height, settings).await)
+        }
+        served => served,
+    }
+}
+
+/// The sides asked for by a request whose path or query can't be read, left for
+/// `draw_unreadable` when the error is to be drawn.
+struct UnreadableSize(Option<u32>, Option<u32>);
+
+/// The `error` of a request whose path or query can't be read. When the request is for an image
+/// the error is drawn by `draw_unreadable`, the extractors can't wait on the blocking threads.
+fn unreadable_request(error: PlacecageError, req: &HttpRequest) -> actix_web::Error {
+    if ErrorFormat::accepted(req) == ErrorFormat::Image {
+        let side = |name: &str| req.match_info().get(name)?.parse().ok();
+        req.extensions_mut()
+            .insert(UnreadableSize(side("width"), side("height")));
+    }
+    error.into()
+}
+
+/// `response` with its error drawn on an image, when `unreadable_request` left it for that.
+async fn draw_unreadable<B: MessageBody + 'static>(
+    response: ServiceResponse<B>,
+) -> ServiceResponse<BoxBody> {
+    let size = response
+        .request()
+        .extensions_mut()
+        .remove::<UnreadableSize>();
+    let error = response
+        .response()
+        .error()
+        .and_then(|e| e.as_error::<PlacecageError>());
+    let (Some(UnreadableSize(width, height)), Some(error)) = (size, error) else {
+        return response.map_into_boxed_body();
+    };
+    let settings = response
+        .request()
+        .app_data::<web::Data<Settings>>()
+        .expect("the settings are in the app data");
+    let image = error_image(error, width, height, settings).await;
+    response.into_response(image)
+}
+
+/// A default placeholder size for the errors of the requests that don't give one.
+const ERROR_IMAGE_SIZE: (u32, u32) = (300, 200);
+
+/// `error` drawn on an image the size asked for, or the largest the limits allow when it's over
+/// them.
+async fn error_image(
+    error: &PlacecageError,
+    width: Option<u32>,
+    height: Option<u32>,
+    settings: &Settings,
+) -> HttpResponse {
+    let limits = settings.limits;
+    let (width, height) = match (width, height) {
+        (Some(width), Some(height)) => (width, height),
+        (Some(side), None) | (None, Some(side)) => (side, side),
+        (None, None) => ERROR_IMAGE_SIZE,
+    };
+    let (mut width, mut height) = (
+        width.clamp(1, limits.max_width.max(1)),
+        height.clamp(1, limits.max_height.max(1)),
+    );
+    let pixels = u64::from(width) * u64::from(height);
+    if pixels > limits.max_pixels {
+        let shrink = (limits.max_pixels as f64 / pixels as f64).sqrt();
+        let side = |side: u32| ((f64::from(side) * shrink) as u32).max(1);
+        (width, height) = (side(width), side(height));
+    }
+    let status = error.status_code();
+    let not_found = match status {
+        StatusCode::NOT_FOUND => settings.not_found_image.clone(),
+        _ => None,
+    };
+    let message = error.to_string();
+    let font = settings
+        .fonts
+        .get(None)
+        .expect("the default font is always there");
+    // Drawn on the blocking threads like the images are made.
+    let drawn = web::block(move || {
+        let (picture, format) = match not_found {
+            Some(not_found) => {
+                let resize = Resize {
+                    width,
+                    height,
+                    fit: Fit::Fill,
+                    pad: None,
+                    gravity: Gravity::Center,
+                    focal: None,
+                    crop: None,
+                    filter: FilterType::CatmullRom,
+                    resample: None,
+                    gpu: false,
+                };
+                let picture = pool::run(|| resize.apply(&not_found, resize.filter));
+                let format = if picture.color().has_alpha() {
+                    OutputFormat::Png
+                } else {
+                    OutputFormat::Jpeg
+                };
+                (
+                    prepare_for_encoding(picture, format),
+                    ImageFormat::from(format),
+                )
             }
+            None => (
+                error::draw(status, &message, width, height, &font),
+                ImageFormat::Png,
+            ),
+        };
+        let mut encoded = Cursor::new(Vec::new());
+        picture.write_to(&mut encoded, format)?;
+        Ok::<_, ImageError>((encoded.into_inner(), format))
+    })
+    .await;
+    match drawn {
+        Ok(Ok((encoded, format))) => error.image_response(encoded, format),
+        Ok(Err(e)) => {
+            log::error!("could not write the error image: {e}");
+            error.error_response()
+        }
+        Err(e) => {
+            log::error!("could not draw the error image: {e}");
+            error.error_response()
+        }
+    }
+}
+
+async fn serve_made_image(
+    req: &HttpRequest,
+    settings: &web::Data<Settings>,
+    cache: &Arc<dyn CacheBackend>,
+    image: ImageRequest,
+    query: GetImageQuery,
+) -> Result<HttpResponse, PlacecageError> {
+    let mut image = image;
+    if query.w.is_some() {
+        image.width = None;
+    }
+    if query.h.is_some() {
+        image.height = None;
+    }
+    let selection = Selection {
+        image: query.image,
+        random: query.random,
+        seed: image.seed.clone(),
+        key: query.key,
+        exclude: query.exclude.map(|exclude| exclude.0).unwrap_or_default(),
+    };
+    // Before the sides are used for anything, the ones past the limits can't be summed safely.
+    check_size(image.width, image.height, settings.limits)?;
+    let random = selection.random;
+    // Picking the source looks at the disk and the size can take reading its header, so they're
+    // on the blocking threads too.
+    let looked_up = {
+        let (settings, rotate) = (web::Data::clone(settings), query.rotate);
+        web::block(move || -> Result<_, PlacecageError> {
+            let source = select_source(&image, &selection, &settings.sources, &settings.weights)?;
+            let size = output_size(&image, &source.path, rotate)?;
+            Ok((image, source, size))
+        })
+    };
+    let (image, source, (width, height)) = looked_up.await.map_err(io::Error::other)??;
+    // The density only scales the output, the source is the same one picked for the base size.
+    let requested_density = req.extensions().get::<Density>().map(|density| density.0);
+    let density = requested_density
+        .map(f64::from)
+        .or_else(|| client_hint_density(req, width))
+        .unwrap_or(1.0);
+    let scale = |side: u32| (f64::from(side) * density).round() as u32;
+    let (width, height) = (scale(width), scale(height));
+
+    // The label is just a text overlay with the size, so explicit text takes its place.
+    let text = query
+        .text
+        .or_else(|| query.label.then(|| format!("{width}×{height}")));
+    let effects = Effects {
+        rotate: query.rotate,
+        flip: query.flip,
+        filter: query.filter.and_then(|filters| filters.effect),
+        blur: query.blur,
+        pixelate: query.pixelate,
+        brightness: query.brightness,
+        contrast: query.contrast,
+        hue: query.hue,
+        tint: query.tint,
+        duotone: query.duotone,
+        text: text
+            .map(|text| -> io::Result<TextOverlay> {
+                Ok(TextOverlay {
+                    text,
+                    font: settings.fonts.get(query.font.as_deref())?,
+                    font_name: query.font.as_ref().map(|name| name.to_lowercase()),
+                })
+            })
+            .transpose()?,
+        captions: image
+            .captions
+            .as_ref()
+            .map(|captions| -> io::Result<Captions> {
+                Ok(Captions {
+                    top: captions.top.clone(),
+                    bottom: captions.bottom.clone(),
+                    font: settings.fonts.get(query.font.as_deref())?,
+                    font_name: query.font.as_ref().map(|name| name.to_lowercase()),
+                })
+            })
+            .transpose()?,
+        border: query.border,
+        radius: query.radius,
+        shape: query.shape,
+    };
+
+    let format = image.format.or(query.format);
+    // Gif sources are kept animated unless the client explicitly asks for another format.
+    let negotiated = format.is_none() && (source.kind != ImageKind::Gif || image.poster);
+    let format = match format {
+        Some(format) => format,
+        None if negotiated => negotiate_format(req, effects.needs_alpha()),
+        None => OutputFormat::Gif,
+    };
+    // The JPEG header only has 16 bits for each side.
+    if matches!(format, OutputFormat::Jpeg) && width.max(height) > u32::from(u16::MAX) {
+        return Err(PlacecageError::InvalidRequest(format!(
+            "jpeg images can't be over {} pixels wide or tall",
+            u16::MAX
+        )));
+    }
+    let encoding = Encoding {
+        format,
+        quality: query.q.or(query.preset.map(Preset::quality)),
+        progressive: query.progressive,
+        chroma: query.preset.map(Preset::chroma),
+        jpeg_backend: settings.jpeg_backend,
+    };
+
+    let resample = query
+        .filter
+        .and_then(|filters| filters.resample)
+        .or(query.preset.map(Preset::resample));
+    let resize = Resize {
+        width,
+        height,
+        fit: query.fit.unwrap_or_default(),
+        pad: query.pad,
+        gravity: query.gravity.unwrap_or_default(),
+        focal: match (query.fx, query.fy) {
+            (None, None) => None,
+            (x, y) => Some((x.unwrap_or(0.5), y.unwrap_or(0.5))),
         },
-        Ok(image) => return Ok(image),
+        crop: query.crop,
+        filter: resample.map_or_else(
+            || resize_filter(width, height, settings.limits),
+            FilterType::from,
+        ),
+        resample,
+        gpu: settings.limits.on_gpu(width, height),
+    };
+
+    let cache_key = cache_key(
+        &image,
+        density,
+        &source,
+        resize,
+        &effects,
+        encoding,
+        settings.limits,
+    )?;
+    // The key names everything the image is made from, so it changes whenever the image does.
+    let etag = EntityTag::new_strong(format!("{:016x}", hash::stable_hash(cache_key.as_bytes())));
+
+    let mut response = HttpResponse::Ok();
+    response
+        .insert_header(header::ETag(etag.clone()))
+        .insert_header(("Accept-CH", CLIENT_HINTS));
+    if negotiated {
+        response.append_header((header::VARY, "Accept"));
+    }
+    // An explicit density wins over whatever the client hints.
+    if requested_density.is_none() {
+        response.append_header((header::VARY, CLIENT_HINTS));
+    }
+    // The same URL gives another picture next time, caches must not keep it.
+    if random {
+        response.insert_header((header::CACHE_CONTROL, "no-store"));
+    } else {
+        response.insert_header(settings.cache_control.clone());
+    }
+    let unchanged = match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        None => false,
+    };
+    if unchanged {
+        return Ok(response.status(StatusCode::NOT_MODIFIED).finish());
+    }
+
+    let mime = ImageFormat::from(format).to_mime_type();
+    // Only the headers go back, the image isn't worth making for them. The length is only known
+    // when it's been made already.
+    let made = if req.extensions().contains::<Head>() {
+        let cache = Arc::clone(cache);
+        let cached = web::block(move || cache.get(&cache_key));
+        match cached.await.map_err(io::Error::other)? {
+            Some(cached) => Ok(cached),
+            None => return Ok(response.content_type(mime).body(body::None::new())),
+        }
+    } else {
+        // Made on the blocking threads, the worker goes on with the requests that don't need
+        // an image made, and the request can stop waiting on it past the timeout. The image is
+        // still finished and cached for the next one then.
+        let cache = Arc::clone(cache);
+        let made = web::block(move || {
+            get_image(
+                &image, &source, resize, &effects, encoding, &cache_key, &*cache,
+            )
+        });
+        let made = match settings.limits.generation_timeout {
+            Some(timeout) => {
+                match actix_web::rt::time::timeout(Duration::from_secs(timeout), made).await {
+                    Ok(made) => made,
+                    Err(_) => {
+                        log::warn!("{} took over {timeout}s to make", req.path());
+                        return Err(PlacecageError::Timeout(timeout));
+                    }
+                }
+            }
+            None => made.await,
+        };
+        made.map_err(io::Error::other)?
+    };
+    let cached = made?;
+    // A regenerated image is newer than whatever the client has, so it gets it again. The dates
+    // only go down to the second.
+    response
+        .insert_header(header::LastModified(cached.made.into()))
+        .insert_header(("X-Cache", cached.found_in.unwrap_or("miss")));
+    // The ETag has the last word when the client sends both.
+    let modified_since = req
+        .get_header::<header::IfModifiedSince>()
+        .filter(|_| !req.headers().contains_key(header::IF_NONE_MATCH));
+    if let Some(header::IfModifiedSince(since)) = modified_since {
+        let seconds = |time: SystemTime| time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        if seconds(cached.made) <= seconds(since.into()) {
+            return Ok(response.status(StatusCode::NOT_MODIFIED).finish());
+        }
     }
+    Ok(response.content_type(mime).body(cached.bytes))
 }
 
-fn get_image(
-    width: u32,
-    height: u32,
-    subject_op: Option<Subject>,
-    kind_op: Option<ImageKind>,
-) -> io::Result<String> {
-    if width + height > 7000 {
-        return Err(io::Error::new(io::ErrorKind::InvalidInput, ""));
+/// Client hints the images are scaled by, asked for in every response.
+const CLIENT_HINTS: &str = "Sec-CH-DPR, Sec-CH-Width";
+/// Client hints can ask for any density, there is no point in going past what `@3x` allows.
+const MAX_HINTED_DENSITY: f64 = 3.0;
+
+/// Density from the `Sec-CH-Width` client hint, the width in physical pixels the image is shown
+/// at, or else from `Sec-CH-DPR`. `None` when neither is sent or the image doesn't need scaling up.
+fn client_hint_density(req: &HttpRequest, width: u32) -> Option<f64> {
+    let hint = |name: &str| {
+        req.headers()
+            .get(name)?
+            .to_str()
+            .ok()?
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|value| value.is_finite())
+    };
+    let density = match hint("Sec-CH-Width") {
+        Some(hinted_width) if width > 0 => hinted_width / f64::from(width),
+        _ => hint("Sec-CH-DPR")?,
+    };
+    // Rounded so small differences in the hints don't each get their own cached file.
+    let density = (density.min(MAX_HINTED_DENSITY) * 100.0).round() / 100.0;
+    (density > 1.0).then_some(density)
+}
+
+/// Marks a `HEAD` request, which is routed as the `GET` it'd be.
+struct Head;
+
+/// Turns a `HEAD` request into a `GET` so the image routes take it, the server still leaves the
+/// body out of the response.
+fn head_as_get(req: &mut ServiceRequest) {
+    if req.method() == Method::HEAD {
+        req.head_mut().method = Method::GET;
+        req.extensions_mut().insert(Head);
     }
+}
 
-    let subject = subject_op.unwrap_or(Subject::Cage);
-    let kind = kind_op.unwrap_or(ImageKind::Default);
+/// Settings of the server taking `req`, for the middleware.
+fn settings(req: &ServiceRequest) -> &Settings {
+    req.app_data::<web::Data<Settings>>()
+        .expect("the settings are in the app data")
+}
 
-    let current_dir = env::current_dir()?;
-    let cur_path = current_dir.into_os_string().into_string().unwrap();
-    let input_dir = format!("{cur_path}/public/images/source/{subject}/{kind}/");
-    let output_dir = format!("{cur_path}/public/images/_gen/{subject}/{kind}/");
-    fs::create_dir_all(&output_dir)?;
+/// Turns down the clients that are denied, or not allowed.
+fn check_ip(req: &ServiceRequest) -> Result<(), Box<HttpResponse>> {
+    let settings = settings(req);
+    if settings.allowed_ips.is_empty() && settings.denied_ips.is_empty() {
+        return Ok(());
+    }
+    let allowed = match ratelimit::client_ip(req, settings.trust_proxy) {
+        Some(ip) => {
+            (settings.allowed_ips.is_empty() || iplist::contains(&settings.allowed_ips, ip))
+                && !iplist::contains(&settings.denied_ips, ip)
+        }
+        // Can't be told to be allowed.
+        None => settings.allowed_ips.is_empty(),
+    };
+    if allowed {
+        Ok(())
+    } else {
+        Err(Box::new(HttpResponse::Forbidden().finish()))
+    }
+}
 
-    let image_count = get_image_count(subject, kind);
-    let selected_image = ((width + height) % image_count) + 1;
-    let input_file = format!("{input_dir}/{selected_image}.jpg");
-    let output_file = format!("{output_dir}/{width}x{height}.jpg");
-    resize_to_fill_io(width, height, input_file.as_str(), output_file.as_str())?;
+/// Turns down the requests for images that aren't signed with the signing key, when there is one.
+fn check_signature(req: &ServiceRequest) -> Result<(), Box<HttpResponse>> {
+    match &settings(req).signing_key {
+        Some(key)
+            if !req.path().starts_with("/admin/")
+                && !signing::verify(key, req.path(), req.query_string()) =>
+        {
+            Err(Box::new(
+                HttpResponse::Forbidden().body("the URL is not signed"),
+            ))
+        }
+        _ => Ok(()),
+    }
+}
 
-    return Ok(output_file);
+/// Turns down the requests embedding the images in sites other than the allowed ones, or sends
+/// them to the hotlink redirect.
+fn check_referer(req: &ServiceRequest) -> Result<(), Box<HttpResponse>> {
+    let settings = settings(req);
+    // The redirect can be to an image of this server, which the request would be sent to over
+    // and over.
+    let redirected = settings
+        .hotlink_redirect
+        .as_ref()
+        .is_some_and(|redirect| redirect.path_and_query() == req.uri().path_and_query());
+    if settings.hotlink_domains.is_empty()
+        || req.path().starts_with("/admin/")
+        || redirected
+        || hotlink::is_allowed(req.request(), &settings.hotlink_domains)
+    {
+        return Ok(());
+    }
+    Err(Box::new(match &settings.hotlink_redirect {
+        Some(redirect) => HttpResponse::Found()
+            .insert_header((header::LOCATION, redirect.to_string()))
+            .finish(),
+        None => HttpResponse::Forbidden().body("the images can't be embedded in this site"),
+    }))
+}
+
+/// Takes a token from the bucket of the client making `req`, or answers that it has to wait.
+fn rate_limit(req: &ServiceRequest) -> Result<(), Box<HttpResponse>> {
+    let settings = settings(req);
+    let Some(limiter) = &settings.rate_limiter else {
+        return Ok(());
+    };
+    // Requests without a peer address can't be told apart, they are let through.
+    match ratelimit::client_ip(req, settings.trust_proxy) {
+        Some(client) => limiter
+            .check(client)
+            .map_err(|retry_after| Box::new(ratelimit::too_many_requests(retry_after))),
+        None => Ok(()),
+    }
+}
+
+/// Counts `req` against the daily quota of its API key, giving how many requests the key has
+/// left, or answers that it can't be made. The admin endpoints have their own token instead.
+fn use_quota(req: &ServiceRequest) -> Result<Option<u64>, Box<HttpResponse>> {
+    match &settings(req).quotas {
+        Some(quotas) if !req.path().starts_with("/admin/") => quotas.check(req).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// Pixel density asked for with an `@2x` or `@3x` after the size.
+#[derive(Clone, Copy)]
+struct Density(u32);
+
+/// Takes an `@2x` or `@3x` density off the end of the path, `/300/200@2x.jpg` becomes
+/// `/300/200.jpg`, so the routes don't each need a variant for it. The density is kept in the
+/// request extensions.
+fn strip_density(req: &mut ServiceRequest) {
+    let path = req.path();
+    let Some((prefix, last)) = path.rsplit_once('/') else {
+        return;
+    };
+    let Some((size, rest)) = last.split_once('@') else {
+        return;
+    };
+    let (density, extension) = match rest.split_once('.') {
+        Some((density, extension)) => (density, Some(extension)),
+        None => (rest, None),
+    };
+    let density = match density {
+        "1x" => 1,
+        "2x" => 2,
+        "3x" => 3,
+        _ => return,
+    };
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_digit()) {
+        return;
+    }
+
+    let mut stripped = format!("{prefix}/{size}");
+    if let Some(extension) = extension {
+        stripped.push_str(&format!(".{extension}"));
+    }
+    let query = req.query_string();
+    if !query.is_empty() {
+        stripped.push_str(&format!("?{query}"));
+    }
+    let mut parts = req.uri().clone().into_parts();
+    let Ok(path_and_query) = PathAndQuery::try_from(stripped) else {
+        return;
+    };
+    parts.path_and_query = Some(path_and_query);
+    let Ok(uri) = Uri::from_parts(parts) else {
+        return;
+    };
+    req.match_info_mut().get_mut().update(&uri);
+    req.head_mut().uri = uri;
+    req.extensions_mut().insert(Density(density));
+}
+
+/// Accepts `1`/`0` as well as `true`/`false` for on/off query parameters.
+fn deserialize_flag<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    match String::deserialize(deserializer)?.as_str() {
+        "1" | "true" => Ok(true),
+        "0" | "false" => Ok(false),
+        other => Err(serde::de::Error::invalid_value(
+            serde::de::Unexpected::Str(other),
+            &"1, 0, true or false",
+        )),
+    }
+}
+
+/// `?filter=` takes the color filter, the resampling filter or both, like `sepia,lanczos3`.
+#[derive(Clone, Copy)]
+struct Filters {
+    effect: Option<Filter>,
+    resample: Option<Resample>,
+}
+
+impl<'de> Deserialize<'de> for Filters {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut filters = Filters {
+            effect: None,
+            resample: None,
+        };
+        for name in String::deserialize(deserializer)?.split(',') {
+            let name = name.trim();
+            let named = || StrDeserializer::<D::Error>::new(name);
+            if let Ok(effect) = Filter::deserialize(named()) {
+                filters.effect = Some(effect);
+            } else if let Ok(resample) = Resample::deserialize(named()) {
+                filters.resample = Some(resample);
+            } else {
+                return Err(serde::de::Error::custom(format!(
+                    "`{name}` is not a filter, expected sepia, invert, nearest, triangle, \
+                     catmullrom or lanczos3"
+                )));
+            }
+        }
+        Ok(filters)
+    }
+}
+
+#[derive(Deserialize)]
+struct GetImageQuery {
+    format: Option<OutputFormat>,
+    q: Option<u8>,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    progressive: bool,
+    preset: Option<Preset>,
+    /// Whether the errors come as JSON or drawn on an image, from the `Accept` header by default.
+    errors: Option<ErrorFormat>,
+    filter: Option<Filters>,
+    blur: Option<u32>,
+    pixelate: Option<u32>,
+    brightness: Option<i32>,
+    contrast: Option<i32>,
+    hue: Option<u32>,
+    tint: Option<Color>,
+    duotone: Option<Duotone>,
+    rotate: Option<Rotation>,
+    flip: Option<Flip>,
+    text: Option<String>,
+    /// Writes the image size on it.
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    label: bool,
+    /// Font for the text, by file name in the fonts directory.
+    font: Option<String>,
+    border: Option<Border>,
+    radius: Option<u32>,
+    shape: Option<Shape>,
+    fit: Option<Fit>,
+    /// Color around the image when fit is `pad`.
+    pad: Option<Color>,
+    gravity: Option<Gravity>,
+    /// Focal point the crop is centered on, from 0 to 1 across the source.
+    fx: Option<f64>,
+    fy: Option<f64>,
+    crop: Option<Crop>,
+    /// Number of the source picture to use instead of the one picked by the size.
+    image: Option<u32>,
+    /// Picks a source at random on every request.
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    random: bool,
+    /// Anything identifying a user, like an email address, to always give them the same picture.
+    key: Option<String>,
+    /// Sources the size, seed, key or randomness can't pick.
+    exclude: Option<Exclude>,
+    /// `auto` computes the width from the aspect ratio of the source.
+    w: Option<Auto>,
+    /// `auto` computes the height from the aspect ratio of the source.
+    h: Option<Auto>,
+}
+
+/// Value for a side of the image that should follow the aspect ratio of the source.
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Auto {
+    Auto,
 }
 
 #[derive(Deserialize)]
@@ -127,23 +1849,174 @@ struct GetImageRequestInfo {
     kind: ImageKind,
     width: u32,
     height: u32,
+    format: Option<OutputFormat>,
 }
 
-#[get("/{subject}/{kind}/{width}/{height}")]
+#[routes]
+#[get("/{subject}/{kind}/{width}/{height:\\d+}")]
+#[get("/{subject}/{kind}/{width}/{height:\\d+}.{format}")]
 async fn get_image_endpoint(
-    _req: HttpRequest,
+    req: HttpRequest,
     path: web::Path<GetImageRequestInfo>,
-) -> io::Result<NamedFile> {
+    query: web::Query<GetImageQuery>,
+    settings: web::Data<Settings>,
+    cache: web::Data<dyn CacheBackend>,
+) -> Result<HttpResponse, PlacecageError> {
     let GetImageRequestInfo {
         subject,
         kind,
         width,
         height,
+        format,
+    } = path.into_inner();
+
+    let image = ImageRequest {
+        width: Some(width),
+        height: Some(height),
+        subject: Some(subject),
+        kind: Some(kind),
+        format,
+        poster: false,
+        grayscale: false,
+        captions: None,
+        seed: None,
+    };
+
+    serve_image(&req, &settings, &cache, image, query.into_inner()).await
+}
+
+#[derive(Deserialize)]
+struct GetRatioImageRequestInfo {
+    subject: Subject,
+    kind: ImageKind,
+    ratio_width: u32,
+    ratio_height: u32,
+    width: u32,
+    format: Option<OutputFormat>,
+}
+
+#[routes]
+#[get("/{subject}/{kind}/{ratio_width:\\d+}x{ratio_height:\\d+}/{width:\\d+}")]
+#[get("/{subject}/{kind}/{ratio_width:\\d+}x{ratio_height:\\d+}/{width:\\d+}.{format}")]
+async fn get_ratio_image_endpoint(
+    req: HttpRequest,
+    path: web::Path<GetRatioImageRequestInfo>,
+    query: web::Query<GetImageQuery>,
+    settings: web::Data<Settings>,
+    cache: web::Data<dyn CacheBackend>,
+) -> Result<HttpResponse, PlacecageError> {
+    let GetRatioImageRequestInfo {
+        subject,
+        kind,
+        ratio_width,
+        ratio_height,
+        width,
+        format,
+    } = path.into_inner();
+    if ratio_width == 0 || ratio_height == 0 {
+        return Err(PlacecageError::InvalidRequest(
+            "both sides of the ratio must be positive".to_string(),
+        ));
+    }
+    let height = (f64::from(width) * f64::from(ratio_height) / f64::from(ratio_width)).round();
+
+    let image = ImageRequest {
+        width: Some(width),
+        height: Some(height.min(f64::from(u32::MAX)) as u32),
+        subject: Some(subject),
+        kind: Some(kind),
+        format,
+        poster: false,
+        grayscale: false,
+        captions: None,
+        seed: None,
+    };
+
+    serve_image(&req, &settings, &cache, image, query.into_inner()).await
+}
+
+#[derive(Deserialize)]
+struct GetWidthOnlyImageRequestInfo {
+    subject: Subject,
+    kind: ImageKind,
+    width: u32,
+    format: Option<OutputFormat>,
+}
+
+#[routes]
+#[get("/{subject}/{kind:[a-z]+}/{width:\\d+}")]
+#[get("/{subject}/{kind:[a-z]+}/{width:\\d+}.{format}")]
+async fn get_width_only_image_endpoint(
+    req: HttpRequest,
+    path: web::Path<GetWidthOnlyImageRequestInfo>,
+    query: web::Query<GetImageQuery>,
+    settings: web::Data<Settings>,
+    cache: web::Data<dyn CacheBackend>,
+) -> Result<HttpResponse, PlacecageError> {
+    let GetWidthOnlyImageRequestInfo {
+        subject,
+        kind,
+        width,
+        format,
+    } = path.into_inner();
+
+    let image = ImageRequest {
+        width: Some(width),
+        height: None,
+        subject: Some(subject),
+        kind: Some(kind),
+        format,
+        poster: false,
+        grayscale: false,
+        captions: None,
+        seed: None,
+    };
+
+    serve_image(&req, &settings, &cache, image, query.into_inner()).await
+}
+
+#[derive(Deserialize)]
+struct GetHeightOnlyImageRequestInfo {
+    subject: Option<Subject>,
+    kind: Option<ImageKind>,
+    height: u32,
+    format: Option<OutputFormat>,
+}
+
+#[routes]
+#[get("/h/{height:\\d+}")]
+#[get("/h/{height:\\d+}.{format}")]
+#[get("/{subject}/h/{height:\\d+}")]
+#[get("/{subject}/h/{height:\\d+}.{format}")]
+#[get("/{subject}/{kind}/h/{height:\\d+}")]
+#[get("/{subject}/{kind}/h/{height:\\d+}.{format}")]
+async fn get_height_only_image_endpoint(
+    req: HttpRequest,
+    path: web::Path<GetHeightOnlyImageRequestInfo>,
+    query: web::Query<GetImageQuery>,
+    settings: web::Data<Settings>,
+    cache: web::Data<dyn CacheBackend>,
+) -> Result<HttpResponse, PlacecageError> {
+    let GetHeightOnlyImageRequestInfo {
+        subject,
+        kind,
+        height,
+        format,
     } = path.into_inner();
 
-    let output_file = get_image(width, height, Some(subject), Some(kind))?;
+    let image = ImageRequest {
+        width: None,
+        height: Some(height),
+        subject,
+        kind,
+        format,
+        poster: false,
+        grayscale: false,
+        captions: None,
+        seed: None,
+    };
 
-    Ok(NamedFile::open_async(output_file).await?)
+    serve_image(&req, &settings, &cache, image, query.into_inner()).await
 }
 
 #[derive(Deserialize)]
@@ -151,51 +2024,617 @@ struct GetNoKindImageRequestInfo {
     subject: Subject,
     width: u32,
     height: u32,
+    format: Option<OutputFormat>,
 }
 
-#[get("/{subject}/{width}/{height}")]
+#[routes]
+#[get("/{subject}/{width}/{height:\\d+}")]
+#[get("/{subject}/{width}/{height:\\d+}.{format}")]
 async fn get_no_kind_image_endpoint(
-    _req: HttpRequest,
+    req: HttpRequest,
     path: web::Path<GetNoKindImageRequestInfo>,
-) -> io::Result<NamedFile> {
+    query: web::Query<GetImageQuery>,
+    settings: web::Data<Settings>,
+    cache: web::Data<dyn CacheBackend>,
+) -> Result<HttpResponse, PlacecageError> {
     let GetNoKindImageRequestInfo {
         subject,
         width,
         height,
+        format,
+    } = path.into_inner();
+
+    let image = ImageRequest {
+        width: Some(width),
+        height: Some(height),
+        subject: Some(subject),
+        kind: None,
+        format,
+        poster: false,
+        grayscale: false,
+        captions: None,
+        seed: None,
+    };
+
+    serve_image(&req, &settings, &cache, image, query.into_inner()).await
+}
+
+#[derive(Deserialize)]
+struct GetSquareImageRequestInfo {
+    subject: Subject,
+    size: u32,
+    format: Option<OutputFormat>,
+}
+
+#[routes]
+#[get("/{subject:[a-z]+}/{size:\\d+}")]
+#[get("/{subject:[a-z]+}/{size:\\d+}.{format}")]
+async fn get_square_image_endpoint(
+    req: HttpRequest,
+    path: web::Path<GetSquareImageRequestInfo>,
+    query: web::Query<GetImageQuery>,
+    settings: web::Data<Settings>,
+    cache: web::Data<dyn CacheBackend>,
+) -> Result<HttpResponse, PlacecageError> {
+    let GetSquareImageRequestInfo {
+        subject,
+        size,
+        format,
     } = path.into_inner();
 
-    let output_file = get_image(width, height, Some(subject), None)?;
+    let image = ImageRequest {
+        width: Some(size),
+        height: Some(size),
+        subject: Some(subject),
+        kind: None,
+        format,
+        poster: false,
+        grayscale: false,
+        captions: None,
+        seed: None,
+    };
 
-    Ok(NamedFile::open_async(output_file).await?)
+    serve_image(&req, &settings, &cache, image, query.into_inner()).await
 }
 
 #[derive(Deserialize)]
 struct GetNoKindNoSubjectImageRequestInfo {
     width: u32,
     height: u32,
+    format: Option<OutputFormat>,
 }
 
-#[get("/{width}/{height}")]
+#[routes]
+#[get("/{width}/{height:\\d+}")]
+#[get("/{width}/{height:\\d+}.{format}")]
 async fn get_no_kind_no_subject_image_endpoint(
-    _req: HttpRequest,
+    req: HttpRequest,
     path: web::Path<GetNoKindNoSubjectImageRequestInfo>,
-) -> io::Result<NamedFile> {
-    let GetNoKindNoSubjectImageRequestInfo { width, height } = path.into_inner();
+    query: web::Query<GetImageQuery>,
+    settings: web::Data<Settings>,
+    cache: web::Data<dyn CacheBackend>,
+) -> Result<HttpResponse, PlacecageError> {
+    let GetNoKindNoSubjectImageRequestInfo {
+        width,
+        height,
+        format,
+    } = path.into_inner();
 
-    let output_file = get_image(width, height, None, None)?;
+    let image = ImageRequest {
+        width: Some(width),
+        height: Some(height),
+        subject: None,
+        kind: None,
+        format,
+        poster: false,
+        grayscale: false,
+        captions: None,
+        seed: None,
+    };
+
+    serve_image(&req, &settings, &cache, image, query.into_inner()).await
+}
 
-    Ok(NamedFile::open_async(output_file).await?)
+#[derive(Deserialize)]
+struct GetSeedImageRequestInfo {
+    seed: String,
+    subject: Subject,
+    width: u32,
+    height: u32,
+    format: Option<OutputFormat>,
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    HttpServer::new(|| {
+#[routes]
+#[get("/seed/{seed}/{subject}/{width}/{height:\\d+}")]
+#[get("/seed/{seed}/{subject}/{width}/{height:\\d+}.{format}")]
+async fn get_seed_image_endpoint(
+    req: HttpRequest,
+    path: web::Path<GetSeedImageRequestInfo>,
+    query: web::Query<GetImageQuery>,
+    settings: web::Data<Settings>,
+    cache: web::Data<dyn CacheBackend>,
+) -> Result<HttpResponse, PlacecageError> {
+    let GetSeedImageRequestInfo {
+        seed,
+        subject,
+        width,
+        height,
+        format,
+    } = path.into_inner();
+
+    let image = ImageRequest {
+        width: Some(width),
+        height: Some(height),
+        subject: Some(subject),
+        kind: None,
+        format,
+        poster: false,
+        grayscale: false,
+        captions: None,
+        seed: Some(seed),
+    };
+
+    serve_image(&req, &settings, &cache, image, query.into_inner()).await
+}
+
+#[derive(Deserialize)]
+struct GetPosterRequestInfo {
+    subject: Subject,
+    width: u32,
+    height: u32,
+    format: Option<OutputFormat>,
+}
+
+#[routes]
+#[get("/{subject}/gif/{width}/{height}/poster")]
+#[get("/{subject}/gif/{width}/{height}/poster.{format}")]
+async fn get_poster_endpoint(
+    req: HttpRequest,
+    path: web::Path<GetPosterRequestInfo>,
+    query: web::Query<GetImageQuery>,
+    settings: web::Data<Settings>,
+    cache: web::Data<dyn CacheBackend>,
+) -> Result<HttpResponse, PlacecageError> {
+    let GetPosterRequestInfo {
+        subject,
+        width,
+        height,
+        format,
+    } = path.into_inner();
+
+    let image = ImageRequest {
+        width: Some(width),
+        height: Some(height),
+        subject: Some(subject),
+        kind: Some(ImageKind::Gif),
+        format,
+        poster: true,
+        grayscale: false,
+        captions: None,
+        seed: None,
+    };
+
+    serve_image(&req, &settings, &cache, image, query.into_inner()).await
+}
+
+#[derive(Deserialize)]
+struct GetCaptionRequestInfo {
+    subject: Subject,
+    kind: ImageKind,
+    width: u32,
+    height: u32,
+    format: Option<OutputFormat>,
+}
+
+#[derive(Deserialize)]
+struct GetCaptionQuery {
+    top: Option<String>,
+    bottom: Option<String>,
+}
+
+#[routes]
+#[get("/{subject}/{kind}/{width}/{height:\\d+}/caption")]
+#[get("/{subject}/{kind}/{width}/{height:\\d+}/caption.{format}")]
+async fn get_caption_endpoint(
+    req: HttpRequest,
+    path: web::Path<GetCaptionRequestInfo>,
+    query: web::Query<GetImageQuery>,
+    captions: web::Query<GetCaptionQuery>,
+    settings: web::Data<Settings>,
+    cache: web::Data<dyn CacheBackend>,
+) -> Result<HttpResponse, PlacecageError> {
+    let GetCaptionRequestInfo {
+        subject,
+        kind,
+        width,
+        height,
+        format,
+    } = path.into_inner();
+
+    let image = ImageRequest {
+        width: Some(width),
+        height: Some(height),
+        subject: Some(subject),
+        kind: Some(kind),
+        format,
+        poster: false,
+        grayscale: false,
+        captions: Some(captions.into_inner()),
+        seed: None,
+    };
+
+    serve_image(&req, &settings, &cache, image, query.into_inner()).await
+}
+
+#[derive(Deserialize)]
+struct GetGrayImageRequestInfo {
+    subject: Option<Subject>,
+    width: u32,
+    height: u32,
+    format: Option<OutputFormat>,
+}
+
+#[routes]
+#[get("/g/{width}/{height:\\d+}")]
+#[get("/g/{width}/{height:\\d+}.{format}")]
+#[get("/{subject}/g/{width}/{height:\\d+}")]
+#[get("/{subject}/g/{width}/{height:\\d+}.{format}")]
+async fn get_gray_image_endpoint(
+    req: HttpRequest,
+    path: web::Path<GetGrayImageRequestInfo>,
+    query: web::Query<GetImageQuery>,
+    settings: web::Data<Settings>,
+    cache: web::Data<dyn CacheBackend>,
+) -> Result<HttpResponse, PlacecageError> {
+    let GetGrayImageRequestInfo {
+        subject,
+        width,
+        height,
+        format,
+    } = path.into_inner();
+
+    let image = ImageRequest {
+        width: Some(width),
+        height: Some(height),
+        subject,
+        kind: None,
+        format,
+        poster: false,
+        grayscale: true,
+        captions: None,
+        seed: None,
+    };
+
+    serve_image(&req, &settings, &cache, image, query.into_inner()).await
+}
+
+#[derive(Deserialize)]
+struct GetShortKindImageRequestInfo {
+    short_kind: ShortKind,
+    width: u32,
+    height: u32,
+    format: Option<OutputFormat>,
+}
+
+#[routes]
+#[get("/{short_kind:c|gif}/{width}/{height:\\d+}")]
+#[get("/{short_kind:c|gif}/{width}/{height:\\d+}.{format}")]
+async fn get_short_kind_image_endpoint(
+    req: HttpRequest,
+    path: web::Path<GetShortKindImageRequestInfo>,
+    query: web::Query<GetImageQuery>,
+    settings: web::Data<Settings>,
+    cache: web::Data<dyn CacheBackend>,
+) -> Result<HttpResponse, PlacecageError> {
+    let GetShortKindImageRequestInfo {
+        short_kind,
+        width,
+        height,
+        format,
+    } = path.into_inner();
+
+    let image = ImageRequest {
+        width: Some(width),
+        height: Some(height),
+        subject: Some(Subject::Cage),
+        kind: Some(short_kind.into()),
+        format,
+        poster: false,
+        grayscale: false,
+        captions: None,
+        seed: None,
+    };
+
+    serve_image(&req, &settings, &cache, image, query.into_inner()).await
+}
+
+/// Addresses to listen on, from `PLACECAGE_BIND_ADDR` unless `--host` or `--port` are given.
+fn bind_addresses(settings: &Settings, serve: ServeArgs) -> Vec<SocketAddr> {
+    let hosts = if serve.host.is_empty() {
+        settings.bind.iter().map(SocketAddr::ip).collect()
+    } else {
+        serve.host
+    };
+    match serve.port {
+        Some(port) => hosts
+            .into_iter()
+            .map(|host| SocketAddr::new(host, port))
+            .collect(),
+        None if hosts.len() == settings.bind.len() => settings.bind.clone(),
+        None => hosts
+            .into_iter()
+            .map(|host| SocketAddr::new(host, DEFAULT_PORT))
+            .collect(),
+    }
+}
+
+/// The socket actix would bind to `address`, with `SO_REUSEPORT` when `reuse_port` is set.
+fn tcp_listener(address: SocketAddr, reuse_port: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    // Like actix, so a restarted server can bind while connections of the last one linger.
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(reuse_port)?;
+    #[cfg(not(unix))]
+    let _ = reuse_port;
+    socket
+        .bind(&address.into())
+        .map_err(|e| io::Error::new(e.kind(), format!("could not listen on {address}: {e}")))?;
+    socket.listen(TCP_BACKLOG)?;
+    Ok(socket.into())
+}
+
+fn main() -> io::Result<()> {
+    // Taken before any thread is started, changing the environment isn't safe with others
+    // running.
+    #[cfg(unix)]
+    let run = run(systemd::listeners()?);
+    #[cfg(not(unix))]
+    let run = run();
+    actix_web::rt::System::new().block_on(run)
+}
+
+/// Runs the command, the server when there is none. The sockets of a systemd socket unit,
+/// `inherited`, take the place of the configured ones.
+async fn run(#[cfg(unix)] inherited: Vec<systemd::Listener>) -> io::Result<()> {
+    let cli = Cli::parse();
+    let settings = web::Data::new(Settings::load()?);
+    env_logger::Builder::new()
+        .filter_level(settings.log_level)
+        .init();
+    pool::start(settings.resize_threads)?;
+    if let Some(max) = settings.limits.max_generations {
+        flight::limit(max, settings.limits.generation_queue);
+    }
+    let serve = match cli.command {
+        None => cli.serve,
+        Some(Command::Serve(serve)) => serve,
+        Some(Command::Pregen(args)) => return warm::pregen(&settings, args),
+        Some(Command::Validate) => return cli::validate(&settings),
+        Some(Command::CleanCache(args)) => return cli::clean_cache(&settings, args),
+        Some(Command::Sign { urls }) => return cli::sign(&settings, &urls),
+    };
+    let addresses = bind_addresses(&settings, serve);
+
+    // Read before anything else is done, so a wrong path doesn't wait on the cache.
+    #[cfg(feature = "tls")]
+    let tls = match &settings.tls {
+        Some((cert, key)) => Some(tls::config(cert, key)?),
+        None => None,
+    };
+    // Checked up front, so the missing and broken pictures show up before they're asked for.
+    let problems = settings.sources.check();
+    for problem in &problems {
+        log::warn!("{problem}");
+    }
+    if !problems.is_empty() && settings.strict_sources {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("found {} problems with the source pictures", problems.len()),
+        ));
+    }
+    let cache = web::Data::from(cache::open(&settings.cache)?);
+    // Kept for as long as the server runs. Without it the sources there are at startup are still
+    // served, just not the ones added later.
+    let _watcher = settings
+        .sources
+        .watch()
+        .map_err(|e| log::warn!("not watching the source images for changes: {e}"))
+        .ok();
+    // An image that can't be made shouldn't keep the server from starting, its requests fail the
+    // same way.
+    warm::warm(&settings, &**cache, &settings.warm_sizes, &Subject::ALL);
+
+    let (unix_socket, reuse_port) = (settings.unix_socket.clone(), settings.reuse_port);
+    let (workers, max_connections) = (settings.workers, settings.max_connections);
+    let (keep_alive, client_request_timeout) =
+        (settings.keep_alive, settings.client_request_timeout);
+    let shutdown_timeout = settings.shutdown_timeout;
+    // The server drops its own once it's stopped.
+    let shutdown_cache = cache.clone();
+    let mut server = HttpServer::new(move || {
         App::new()
+            .app_data(settings.clone())
+            .app_data(cache.clone())
+            // The requests the routes can't read get the same errors as the others.
+            .app_data(web::QueryConfig::default().error_handler(|e, req| {
+                unreadable_request(PlacecageError::InvalidRequest(e.to_string()), req)
+            }))
+            .app_data(web::PathConfig::default().error_handler(|e, req| {
+                unreadable_request(PlacecageError::NotFound(e.to_string()), req)
+            }))
+            .wrap_fn(|mut req, service| {
+                head_as_get(&mut req);
+                // Before the density is taken off the path the signature is for.
+                let signed = check_ip(&req).and_then(|()| check_signature(&req));
+                strip_density(&mut req);
+                let call = match signed
+                    .and_then(|()| check_referer(&req))
+                    .and_then(|()| rate_limit(&req))
+                    .and_then(|()| use_quota(&req))
+                {
+                    Ok(remaining) => Ok((service.call(req), remaining)),
+                    Err(response) => Err(req.into_response(*response)),
+                };
+                async move {
+                    match call {
+                        Ok((call, remaining)) => {
+                            let mut response = draw_unreadable(call.await?).await;
+                            if let Some(remaining) = remaining {
+                                response
+                                    .headers_mut()
+                                    .insert(ratelimit::X_RATELIMIT_REMAINING, remaining.into());
+                            }
+                            Ok(response.map_into_left_body())
+                        }
+                        Err(response) => Ok(response.map_into_right_body()),
+                    }
+                }
+            })
+            .wrap(
+                settings
+                    .security_headers
+                    .iter()
+                    .fold(DefaultHeaders::new(), |headers, header| {
+                        headers.add(header.clone())
+                    }),
+            )
+            .wrap(Condition::new(
+                !settings.cors_origins.is_empty(),
+                cors::cors(&settings),
+            ))
+            // Logged at the info level.
+            .wrap(Logger::default())
+            .service(admin::scope())
+            // Registered first so `g`, `c`, `gif` and `h` aren't taken for a subject, kind or
+            // width by the routes below.
+            .service(get_gray_image_endpoint)
+            .service(get_height_only_image_endpoint)
+            .service(get_short_kind_image_endpoint)
+            // Before the image route, which would take the ratio for the width.
+            .service(get_ratio_image_endpoint)
             .service(get_image_endpoint)
+            .service(get_poster_endpoint)
+            .service(get_seed_image_endpoint)
+            .service(get_caption_endpoint)
+            // Before the no kind route, which would take the kind for the width.
+            .service(get_width_only_image_endpoint)
             .service(get_no_kind_image_endpoint)
+            // Before the no subject route, which would take the subject for the width.
+            .service(get_square_image_endpoint)
             .service(get_no_kind_no_subject_image_endpoint)
-    })
-    .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
+    });
+    if let Some(workers) = workers {
+        server = server.workers(workers);
+    }
+    if let Some(max_connections) = max_connections {
+        server = server.max_connections(max_connections);
+    }
+    if let Some(keep_alive) = keep_alive {
+        server = server.keep_alive(if keep_alive.is_zero() {
+            KeepAlive::Disabled
+        } else {
+            KeepAlive::Timeout(keep_alive)
+        });
+    }
+    if let Some(timeout) = client_request_timeout {
+        server = server.client_request_timeout(timeout);
+    }
+    // On SIGTERM or SIGINT the server stops taking connections and waits this long for the
+    // images being made to be sent.
+    if let Some(timeout) = shutdown_timeout {
+        server = server.shutdown_timeout(timeout.as_secs());
+    }
+    #[cfg(unix)]
+    let systemd_sockets = !inherited.is_empty();
+    #[cfg(not(unix))]
+    let systemd_sockets = false;
+    #[cfg(unix)]
+    for listener in inherited {
+        server = match listener {
+            #[cfg(feature = "tls")]
+            systemd::Listener::Tcp(tcp) if tls.is_some() => {
+                server.listen_rustls_0_23(tcp, tls.clone().unwrap())?
+            }
+            systemd::Listener::Tcp(tcp) => server.listen(tcp)?,
+            #[cfg(feature = "tls")]
+            systemd::Listener::Unix(_) if tls.is_some() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "systemd passed a unix socket, which can't be used with TLS",
+                ))
+            }
+            systemd::Listener::Unix(unix) => server.listen_uds(unix)?,
+        };
+    }
+    match &unix_socket {
+        _ if systemd_sockets => {}
+        #[cfg(unix)]
+        Some(path) => {
+            // Left over when the last server didn't get to clean it up, binding fails while it's
+            // there. Only a socket is removed, not a file set there by mistake.
+            if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+                fs::remove_file(path)?;
+            }
+            server = server.bind_uds(path)?;
+        }
+        _ => {
+            for &address in &addresses {
+                let tcp = tcp_listener(address, reuse_port)?;
+                #[cfg(feature = "tls")]
+                if let Some(tls) = &tls {
+                    server = server.listen_rustls_0_23(tcp, tls.clone())?;
+                    continue;
+                }
+                server = server.listen(tcp)?;
+            }
+        }
+    }
+    let server = server.run();
+    #[cfg(unix)]
+    {
+        systemd::notify("READY=1");
+        systemd::start_watchdog();
+    }
+    server.await?;
+    #[cfg(unix)]
+    systemd::notify("STOPPING=1");
+    log::info!("stopped, flushing the cache");
+    shutdown_cache.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_size_rejects_zero_sides() {
+        let limits = Limits::default();
+        for (width, height) in [
+            (Some(0), Some(0)),
+            (Some(0), Some(200)),
+            (Some(300), Some(0)),
+        ] {
+            let error = check_size(width, height, limits).unwrap_err();
+            assert_eq!(error.status_code(), StatusCode::BAD_REQUEST);
+            assert_eq!(error.to_string(), "width and height must be at least 1");
+        }
+        assert!(check_size(Some(0), None, limits).is_err());
+        assert!(check_size(Some(300), Some(200), limits).is_ok());
+    }
+
+    #[test]
+    fn megabytes_past_64_bits_are_refused() {
+        assert_eq!(
+            megabytes_to_bytes("memory_mb", 64).unwrap(),
+            64 * 1024 * 1024
+        );
+        let error = megabytes_to_bytes("memory_mb", u64::MAX).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
 }
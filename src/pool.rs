@@ -0,0 +1,66 @@
+//! The threads the images are made on, as many as there are CPU cores by default. Kept apart from
+//! the threads answering the requests and the blocking threads, so a burst of images to make
+//! doesn't take more CPU than there is.
+
+use serde::Serialize;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+static POOL: OnceLock<Pool> = OnceLock::new();
+
+struct Pool {
+    threads: rayon::ThreadPool,
+    /// Images waiting for a thread.
+    queued: AtomicUsize,
+    /// Images being made.
+    running: AtomicUsize,
+}
+
+/// What the pool is doing, for the admin endpoint.
+#[derive(Serialize)]
+pub struct PoolStats {
+    pub threads: usize,
+    pub running: usize,
+    pub queued: usize,
+}
+
+/// Starts the pool with `threads` threads, one per physical CPU core when `None`. Until then the
+/// images are made on the thread asking for them.
+pub fn start(threads: Option<usize>) -> io::Result<()> {
+    let threads = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads.unwrap_or_else(num_cpus::get_physical))
+        .thread_name(|index| format!("resize-{index}"))
+        .build()
+        .map_err(io::Error::other)?;
+    let _ = POOL.set(Pool {
+        threads,
+        queued: AtomicUsize::new(0),
+        running: AtomicUsize::new(0),
+    });
+    Ok(())
+}
+
+/// Runs `make` on the pool, waiting for it to be done.
+pub fn run<T: Send>(make: impl FnOnce() -> T + Send) -> T {
+    let Some(pool) = POOL.get() else {
+        return make();
+    };
+    pool.queued.fetch_add(1, Ordering::Relaxed);
+    pool.threads.install(|| {
+        pool.queued.fetch_sub(1, Ordering::Relaxed);
+        pool.running.fetch_add(1, Ordering::Relaxed);
+        let made = make();
+        pool.running.fetch_sub(1, Ordering::Relaxed);
+        made
+    })
+}
+
+/// `None` when the pool isn't started.
+pub fn stats() -> Option<PoolStats> {
+    POOL.get().map(|pool| PoolStats {
+        threads: pool.threads.current_num_threads(),
+        running: pool.running.load(Ordering::Relaxed),
+        queued: pool.queued.load(Ordering::Relaxed),
+    })
+}
@@ -0,0 +1,224 @@
+//! Limits how fast each client can make requests, so a single scraper can't keep the server busy
+//! resizing for everyone else, and how many requests each API key can make in a day.
+
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::{self, HeaderName};
+use actix_web::{web, HttpResponse};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Requests the API key of a request can still make today.
+pub const X_RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("x-ratelimit-remaining");
+
+/// Header the API key is sent in, it can also be given with `?api_key=` for `<img>` tags.
+pub const X_API_KEY: HeaderName = HeaderName::from_static("x-api-key");
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Past this many clients the ones whose bucket filled up again are forgotten.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+/// Looking through every client takes a while, past the maximum it's done this often at most.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A token bucket for each client address, every request takes a token and they come back at a
+/// steady rate up to the burst.
+pub struct RateLimiter {
+    /// Tokens given back each second.
+    rate: f64,
+    /// Tokens a bucket holds, how many requests can be made at once after a pause.
+    burst: f64,
+    buckets: Mutex<Buckets>,
+}
+
+struct Buckets {
+    clients: HashMap<IpAddr, Bucket>,
+    /// When the full buckets were last forgotten.
+    pruned: Instant,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        RateLimiter {
+            rate,
+            burst,
+            buckets: Mutex::new(Buckets {
+                clients: HashMap::new(),
+                pruned: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes a token from the bucket of `client`, or tells how long until there is one.
+    pub fn check(&self, client: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let Buckets { clients, pruned } = &mut *buckets;
+        // Only a new client makes the map grow, and a flood of them would have every request go
+        // through all of them without the interval.
+        if clients.len() >= MAX_TRACKED_CLIENTS
+            && !clients.contains_key(&client)
+            && now.duration_since(*pruned) >= PRUNE_INTERVAL
+        {
+            clients.retain(|_, bucket| self.refill(bucket, now) < self.burst);
+            *pruned = now;
+        }
+        let bucket = clients.entry(client).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let tokens = self.refill(bucket, now);
+        if tokens < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - tokens) / self.rate));
+        }
+        bucket.tokens = tokens - 1.0;
+        Ok(())
+    }
+
+    /// The tokens in `bucket` by `now`, which it is updated to.
+    fn refill(&self, bucket: &mut Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        bucket.tokens
+    }
+}
+
+/// Answer to a request over the limit, telling the client when it can try again.
+pub fn too_many_requests(retry_after: Duration) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header((
+            header::RETRY_AFTER,
+            retry_after.as_secs_f64().ceil().max(1.0).to_string(),
+        ))
+        .finish()
+}
+
+/// Address of the client making the request. With `trust_proxy` it's taken from the
+/// `Forwarded` or `X-Forwarded-For` header the proxy in front sets, which anyone could set
+/// otherwise.
+pub fn client_ip(req: &ServiceRequest, trust_proxy: bool) -> Option<IpAddr> {
+    if trust_proxy {
+        if let Some(ip) = req
+            .connection_info()
+            .realip_remote_addr()
+            .and_then(parse_ip)
+        {
+            return Some(ip);
+        }
+    }
+    req.peer_addr().map(|address| address.ip())
+}
+
+/// Parses an address the way the proxy headers write it, possibly with a port.
+fn parse_ip(address: &str) -> Option<IpAddr> {
+    address
+        .parse()
+        .ok()
+        .or_else(|| address.parse::<std::net::SocketAddr>().ok().map(|a| a.ip()))
+        .or_else(|| {
+            address
+                .trim_start_matches('[')
+                .split(']')
+                .next()
+                .and_then(|ip| ip.parse().ok())
+        })
+}
+
+/// The API keys requests have to carry and how many requests each of them can make in a day, the
+/// days being UTC ones. The counts are kept in memory, so they start over when the server
+/// restarts.
+pub struct Quotas {
+    quotas: HashMap<String, u64>,
+    /// Day the count of a key is for, and the count.
+    used: Mutex<HashMap<String, (u64, u64)>>,
+}
+
+#[derive(Deserialize)]
+struct ApiKeyQuery {
+    api_key: Option<String>,
+}
+
+impl Quotas {
+    pub fn new(quotas: HashMap<String, u64>) -> Self {
+        Quotas {
+            quotas,
+            used: Mutex::default(),
+        }
+    }
+
+    /// Counts `req` against the quota of its API key, and tells how many requests the key has
+    /// left today. Without a known key, or with its quota used up, it's the response to send
+    /// instead.
+    pub fn check(&self, req: &ServiceRequest) -> Result<u64, Box<HttpResponse>> {
+        let key = req
+            .headers()
+            .get(X_API_KEY)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+            .or_else(|| {
+                web::Query::<ApiKeyQuery>::from_query(req.query_string())
+                    .ok()
+                    .and_then(|query| query.into_inner().api_key)
+            });
+        let Some((key, &quota)) = key.and_then(|key| self.quotas.get_key_value(&key)) else {
+            return Err(Box::new(
+                HttpResponse::Unauthorized().body("a valid API key is needed"),
+            ));
+        };
+
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let today = since_epoch / SECONDS_PER_DAY;
+        let mut used = self.used.lock().unwrap();
+        let (day, count) = used.entry(key.clone()).or_insert((today, 0));
+        if *day != today {
+            *day = today;
+            *count = 0;
+        }
+        if *count >= quota {
+            let tomorrow = (today + 1) * SECONDS_PER_DAY;
+            return Err(Box::new(
+                HttpResponse::TooManyRequests()
+                    .insert_header((header::RETRY_AFTER, tomorrow - since_epoch))
+                    .insert_header((X_RATELIMIT_REMAINING, 0))
+                    .finish(),
+            ));
+        }
+        *count += 1;
+        Ok(quota - *count)
+    }
+}
+
+/// Parses a comma separated list of API keys and their daily quota, like `team-a:10000,ci:500`.
+pub fn parse_api_keys(value: &str) -> io::Result<HashMap<String, u64>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            entry
+                .rsplit_once(':')
+                .and_then(|(key, quota)| Some((key.to_string(), quota.parse().ok()?)))
+                .filter(|(key, _)| !key.is_empty())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "`{entry}` is not an API key and its daily quota like team-a:10000"
+                        ),
+                    )
+                })
+        })
+        .collect()
+}
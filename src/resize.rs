@@ -0,0 +1,384 @@
+//! How a source image is brought to the requested size.
+
+use crate::effects::Color;
+use fast_image_resize::images::{Image, ImageRef};
+use fast_image_resize::{PixelType, ResizeAlg, ResizeOptions, Resizer};
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, ImageBuffer, Rgba, RgbaImage};
+use serde::Deserialize;
+use std::io;
+
+/// How the source is fit in the requested size when their aspect ratios differ.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Fit {
+    /// Covers the whole size, cropping what overflows.
+    #[default]
+    Fill,
+    /// Fits inside the size, so one side can come out smaller than asked.
+    #[serde(alias = "fit")]
+    Contain,
+    /// Distorts the source to the exact size.
+    Stretch,
+    /// Fits inside the size and pads the rest with a solid color.
+    Pad,
+}
+
+impl std::fmt::Display for Fit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Fit::Fill => write!(f, "fill"),
+            Fit::Contain => write!(f, "contain"),
+            Fit::Stretch => write!(f, "stretch"),
+            Fit::Pad => write!(f, "pad"),
+        }
+    }
+}
+
+/// Which part of the source is kept when filling crops it.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Gravity {
+    #[default]
+    Center,
+    North,
+    South,
+    East,
+    West,
+}
+
+impl Gravity {
+    /// Where the crop sits in the overflow, from 0 (left or top) to 1 (right or bottom).
+    fn anchor(self) -> (f64, f64) {
+        match self {
+            Gravity::Center => (0.5, 0.5),
+            Gravity::North => (0.5, 0.0),
+            Gravity::South => (0.5, 1.0),
+            Gravity::East => (1.0, 0.5),
+            Gravity::West => (0.0, 0.5),
+        }
+    }
+}
+
+impl std::fmt::Display for Gravity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Gravity::Center => write!(f, "center"),
+            Gravity::North => write!(f, "north"),
+            Gravity::South => write!(f, "south"),
+            Gravity::East => write!(f, "east"),
+            Gravity::West => write!(f, "west"),
+        }
+    }
+}
+
+/// Crops that look at the content of the source to decide what to keep.
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Crop {
+    /// Keeps the part of the source with the most detail.
+    Smart,
+    /// Centers the crop on the biggest face, falling back to the smart crop when there is none.
+    #[cfg(feature = "face")]
+    Face,
+}
+
+impl std::fmt::Display for Crop {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Crop::Smart => write!(f, "smart"),
+            #[cfg(feature = "face")]
+            Crop::Face => write!(f, "face"),
+        }
+    }
+}
+
+/// Resampling filters that can be asked for instead of the one picked by the size.
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Resample {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Lanczos3,
+}
+
+impl From<Resample> for FilterType {
+    fn from(resample: Resample) -> Self {
+        match resample {
+            Resample::Nearest => FilterType::Nearest,
+            Resample::Triangle => FilterType::Triangle,
+            Resample::CatmullRom => FilterType::CatmullRom,
+            Resample::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+impl std::fmt::Display for Resample {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Resample::Nearest => write!(f, "nearest"),
+            Resample::Triangle => write!(f, "triangle"),
+            Resample::CatmullRom => write!(f, "catmullrom"),
+            Resample::Lanczos3 => write!(f, "lanczos3"),
+        }
+    }
+}
+
+/// Side of the downscaled copy the smart crop looks at, the exact pixels don't matter to find
+/// where the detail is and this keeps it fast on the big sources.
+const SMART_CROP_ANALYSIS_SIZE: u32 = 128;
+
+/// The size to resize to and how to get there.
+#[derive(Clone, Copy)]
+pub struct Resize {
+    pub width: u32,
+    pub height: u32,
+    pub fit: Fit,
+    /// Color around the image with [`Fit::Pad`], black when not given.
+    pub pad: Option<Color>,
+    /// Only used by [`Fit::Fill`], the other modes keep the whole source.
+    pub gravity: Gravity,
+    /// Point of the source the crop is centered on, as fractions of its width and height. Takes
+    /// precedence over the gravity.
+    pub focal: Option<(f64, f64)>,
+    /// Takes precedence over both the focal point and the gravity.
+    pub crop: Option<Crop>,
+    /// How the pixels are resampled.
+    pub filter: FilterType,
+    /// The filter asked for, when the request picked it instead of leaving it to the size.
+    pub resample: Option<Resample>,
+    /// Resampled on the GPU when there is one, bilinearly whatever the filter.
+    #[cfg_attr(not(feature = "gpu"), allow(dead_code))]
+    pub gpu: bool,
+}
+
+impl Resize {
+    pub fn validate(&self) -> io::Result<()> {
+        match self.focal {
+            Some((x, y)) if !(0.0..=1.0).contains(&x) || !(0.0..=1.0).contains(&y) => {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the focal point must be between 0 and 1",
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn apply(&self, image: &DynamicImage, filter: FilterType) -> DynamicImage {
+        let (width, height) = (self.width, self.height);
+        match self.fit {
+            Fit::Fill => self.fill(image, filter),
+            Fit::Contain => self.contain(image, filter),
+            Fit::Stretch => self.resample(image, None, width, height, filter),
+            Fit::Pad => {
+                let Color([r, g, b]) = self.pad.unwrap_or(Color([0, 0, 0]));
+                let resized = self.contain(image, filter);
+                let mut canvas = RgbaImage::from_pixel(width, height, Rgba([r, g, b, 255]));
+                // Contain comes out at least 1px on each side, over a side of 0.
+                let x = width.saturating_sub(resized.width()) / 2;
+                let y = height.saturating_sub(resized.height()) / 2;
+                imageops::overlay(&mut canvas, &resized.into_rgba8(), x.into(), y.into());
+                DynamicImage::ImageRgba8(canvas)
+            }
+        }
+    }
+
+    /// Crops the largest part of `image` with the requested aspect ratio, placed by the crop mode,
+    /// the focal point or the gravity, and scales it to the requested size.
+    fn fill(&self, image: &DynamicImage, filter: FilterType) -> DynamicImage {
+        let (source_width, source_height) = (f64::from(image.width()), f64::from(image.height()));
+        let (width, height) = (f64::from(self.width), f64::from(self.height));
+        let scale = (width / source_width).max(height / source_height);
+        let crop_width = (width / scale).round().clamp(1.0, source_width);
+        let crop_height = (height / scale).round().clamp(1.0, source_height);
+
+        let centered_on = |x: f64, y: f64| {
+            (
+                (x - crop_width / 2.0).clamp(0.0, source_width - crop_width),
+                (y - crop_height / 2.0).clamp(0.0, source_height - crop_height),
+            )
+        };
+        let (x, y) = match (self.crop, self.focal) {
+            (Some(Crop::Smart), _) => smart_crop_origin(image, crop_width, crop_height),
+            #[cfg(feature = "face")]
+            (Some(Crop::Face), _) => match crate::face::find_face(image) {
+                Some((face_x, face_y)) => centered_on(face_x, face_y),
+                None => smart_crop_origin(image, crop_width, crop_height),
+            },
+            (None, Some((focal_x, focal_y))) => {
+                centered_on(focal_x * source_width, focal_y * source_height)
+            }
+            (None, None) => {
+                let (anchor_x, anchor_y) = self.gravity.anchor();
+                (
+                    (source_width - crop_width) * anchor_x,
+                    (source_height - crop_height) * anchor_y,
+                )
+            }
+        };
+        let (x, y) = (x.round(), y.round());
+        self.resample(
+            image,
+            Some((x, y, crop_width, crop_height)),
+            self.width,
+            self.height,
+            filter,
+        )
+    }
+
+    /// `image` scaled to fit in `width` x `height`, keeping its aspect ratio.
+    fn contain(&self, image: &DynamicImage, filter: FilterType) -> DynamicImage {
+        let (width, height) = (self.width, self.height);
+        let (source_width, source_height) = (f64::from(image.width()), f64::from(image.height()));
+        let ratio = (f64::from(width) / source_width).min(f64::from(height) / source_height);
+        let side = |source: f64| ((source * ratio).round() as u32).max(1);
+        self.resample(image, None, side(source_width), side(source_height), filter)
+    }
+
+    /// The `crop` of `image`, as its left, top, width and height, or the whole of it, scaled to
+    /// exactly `width` x `height` with SIMD when the CPU has it. The images with more than 8 bits
+    /// per channel are scaled by the image crate instead, and with [`Resize::gpu`] they're scaled on
+    /// the GPU when there is one.
+    fn resample(
+        &self,
+        image: &DynamicImage,
+        crop: Option<(f64, f64, f64, f64)>,
+        width: u32,
+        height: u32,
+        filter: FilterType,
+    ) -> DynamicImage {
+        #[cfg(feature = "gpu")]
+        if self.gpu {
+            if let Some(scaled) = crate::gpu::resample(image, crop, width, height) {
+                return scaled;
+            }
+        }
+        let pixel_type = match image {
+            DynamicImage::ImageLuma8(_) => Some(PixelType::U8),
+            DynamicImage::ImageLumaA8(_) => Some(PixelType::U8x2),
+            DynamicImage::ImageRgb8(_) => Some(PixelType::U8x3),
+            DynamicImage::ImageRgba8(_) => Some(PixelType::U8x4),
+            _ => None,
+        };
+        let algorithm = match filter {
+            FilterType::Nearest => ResizeAlg::Nearest,
+            FilterType::Triangle => ResizeAlg::Convolution(fast_image_resize::FilterType::Bilinear),
+            FilterType::CatmullRom => {
+                ResizeAlg::Convolution(fast_image_resize::FilterType::CatmullRom)
+            }
+            FilterType::Gaussian => ResizeAlg::Convolution(fast_image_resize::FilterType::Gaussian),
+            FilterType::Lanczos3 => ResizeAlg::Convolution(fast_image_resize::FilterType::Lanczos3),
+        };
+        let mut options = ResizeOptions::new().resize_alg(algorithm);
+        if let Some((left, top, crop_width, crop_height)) = crop {
+            options = options.crop(left, top, crop_width, crop_height);
+        }
+        let scaled = pixel_type.and_then(|pixel_type| {
+            let source =
+                ImageRef::new(image.width(), image.height(), image.as_bytes(), pixel_type).ok()?;
+            let mut scaled = Image::new(width, height, pixel_type);
+            Resizer::new().resize(&source, &mut scaled, &options).ok()?;
+            let buffer = scaled.into_vec();
+            Some(match image {
+                DynamicImage::ImageLuma8(_) => {
+                    ImageBuffer::from_raw(width, height, buffer).map(DynamicImage::ImageLuma8)
+                }
+                DynamicImage::ImageLumaA8(_) => {
+                    ImageBuffer::from_raw(width, height, buffer).map(DynamicImage::ImageLumaA8)
+                }
+                DynamicImage::ImageRgb8(_) => {
+                    ImageBuffer::from_raw(width, height, buffer).map(DynamicImage::ImageRgb8)
+                }
+                _ => ImageBuffer::from_raw(width, height, buffer).map(DynamicImage::ImageRgba8),
+            })
+            .flatten()
+        });
+        scaled.unwrap_or_else(|| match crop {
+            Some((left, top, crop_width, crop_height)) => image
+                .crop_imm(
+                    left as u32,
+                    top as u32,
+                    crop_width as u32,
+                    crop_height as u32,
+                )
+                .resize_exact(width, height, filter),
+            None => image.resize_exact(width, height, filter),
+        })
+    }
+
+    /// Suffix appended to the cached file name after the size, empty for the default fill.
+    pub fn cache_suffix(&self) -> String {
+        let mut suffix = String::new();
+        if self.fit != Fit::Fill {
+            suffix.push_str(&format!("_{}", self.fit));
+        }
+        if let (Fit::Pad, Some(pad)) = (self.fit, self.pad) {
+            suffix.push_str(&format!("-{pad}"));
+        }
+        if self.fit == Fit::Fill {
+            if let Some(crop) = self.crop {
+                suffix.push_str(&format!("_{crop}"));
+            } else if let Some((x, y)) = self.focal {
+                suffix.push_str(&format!("_focal{x}-{y}"));
+            } else if self.gravity != Gravity::Center {
+                suffix.push_str(&format!("_{}", self.gravity));
+            }
+        }
+        if let Some(resample) = self.resample {
+            suffix.push_str(&format!("_{resample}"));
+        }
+        suffix
+    }
+}
+
+/// Top left corner of the `crop_width` x `crop_height` window of `image` holding the most edges,
+/// which is where the subject usually is while backgrounds tend to be flat or blurred.
+fn smart_crop_origin(image: &DynamicImage, crop_width: f64, crop_height: f64) -> (f64, f64) {
+    let luma = image
+        .resize(
+            SMART_CROP_ANALYSIS_SIZE,
+            SMART_CROP_ANALYSIS_SIZE,
+            FilterType::Triangle,
+        )
+        .into_luma8();
+    let ratio = f64::from(luma.width()) / f64::from(image.width());
+
+    let mut columns = vec![0; luma.width() as usize];
+    let mut rows = vec![0; luma.height() as usize];
+    for y in 1..luma.height() {
+        for x in 1..luma.width() {
+            let pixel = i32::from(luma.get_pixel(x, y)[0]);
+            let left = i32::from(luma.get_pixel(x - 1, y)[0]);
+            let above = i32::from(luma.get_pixel(x, y - 1)[0]);
+            let edge = (pixel - left).unsigned_abs() + (pixel - above).unsigned_abs();
+            columns[x as usize] += edge;
+            rows[y as usize] += edge;
+        }
+    }
+
+    let x = best_window(&columns, crop_width * ratio) as f64 / ratio;
+    let y = best_window(&rows, crop_height * ratio) as f64 / ratio;
+    (
+        x.clamp(0.0, f64::from(image.width()) - crop_width),
+        y.clamp(0.0, f64::from(image.height()) - crop_height),
+    )
+}
+
+/// Start of the `window` long run of `energy` with the highest sum.
+fn best_window(energy: &[u32], window: f64) -> usize {
+    let window = (window.round() as usize).clamp(1, energy.len().max(1));
+    if window >= energy.len() {
+        return 0;
+    }
+    let mut sum: u64 = energy[..window].iter().map(|&e| u64::from(e)).sum();
+    let (mut best_start, mut best_sum) = (0, sum);
+    for start in 1..=energy.len() - window {
+        sum = sum + u64::from(energy[start + window - 1]) - u64::from(energy[start - 1]);
+        if sum > best_sum {
+            (best_start, best_sum) = (start, sum);
+        }
+    }
+    best_start
+}
@@ -0,0 +1,204 @@
+//! How the source photo for a request is picked among the ones of its subject and kind.
+
+use crate::hash::stable_hash32;
+use rand::Rng;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// Name of the file giving the weights of the sources in their directory.
+const WEIGHTS_FILE: &str = "weights.txt";
+/// Highest source number a weights file can give a weight to. Far more pictures than a subject
+/// has, it's there so a typo in the file doesn't take gigabytes to hold the weights up to it.
+const MAX_WEIGHTED_SOURCE: u32 = 10_000;
+
+/// What the request asked for to pick its source, with nothing set the size decides.
+#[derive(Default)]
+pub struct Selection {
+    /// A specific source, numbered from 1.
+    pub image: Option<u32>,
+    /// A different source on every request.
+    pub random: bool,
+    /// Any text, the same one always picks the same source whatever the size.
+    pub seed: Option<String>,
+    /// Like the seed, but compared ignoring case and surrounding spaces so an email address
+    /// picks the same avatar however it was typed.
+    pub key: Option<String>,
+    /// Numbers of the sources never to pick, unless asked for with the image number.
+    pub exclude: Vec<u32>,
+}
+
+impl Selection {
+    /// Number of the source picked out of the `numbers` there are. `size_key` is what the
+    /// default selection goes by, so the same size always gets the same picture. Every selection
+    /// but the pinned image follows the `weights` of the sources.
+    pub fn pick(&self, numbers: &[u32], size_key: u32, weights: &[u32]) -> io::Result<u32> {
+        if self.random {
+            return self.weighted(rand::thread_rng().gen(), numbers, weights);
+        }
+        match self.image {
+            Some(image) if numbers.contains(&image) => Ok(image),
+            Some(image) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("there is no image {image}"),
+            )),
+            None => {
+                let key = match (&self.seed, &self.key) {
+                    (Some(seed), _) => stable_hash32(seed.as_bytes()),
+                    (None, Some(key)) => stable_hash32(key.trim().to_lowercase().as_bytes()),
+                    (None, None) => size_key,
+                };
+                self.weighted(key, numbers, weights)
+            }
+        }
+    }
+
+    /// Maps `key` to one of the sources in `numbers`, each taking as many keys as its weight. The
+    /// sources left out of `weights` weigh 1, so with no weights it's a plain modulo, and the
+    /// excluded ones weigh 0.
+    fn weighted(&self, key: u32, numbers: &[u32], weights: &[u32]) -> io::Result<u32> {
+        let weight = |number: u32| {
+            if self.exclude.contains(&number) {
+                return 0;
+            }
+            weights
+                .get(number as usize - 1)
+                .map_or(1, |&weight| u64::from(weight))
+        };
+        let total: u64 = numbers.iter().copied().map(weight).sum();
+        if total == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "there is no source left to pick from",
+            ));
+        }
+        let mut remaining = u64::from(key) % total;
+        for &number in numbers {
+            if remaining < weight(number) {
+                return Ok(number);
+            }
+            remaining -= weight(number);
+        }
+        unreachable!("the key is below the total weight")
+    }
+}
+
+/// Source numbers to leave out of the selection, written `3,7,12`.
+pub struct Exclude(pub Vec<u32>);
+
+impl<'de> Deserialize<'de> for Exclude {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .split(',')
+            .map(|number| number.trim().parse().map_err(serde::de::Error::custom))
+            .collect::<Result<_, _>>()
+            .map(Exclude)
+    }
+}
+
+/// How often each source comes up relative to the others, for each subject and kind.
+#[derive(Clone)]
+pub struct Weights {
+    sources_dir: PathBuf,
+    weights: Arc<RwLock<HashMap<String, Vec<u32>>>>,
+}
+
+impl Weights {
+    /// Reads the weights file of each `{subject}/{kind}` directory in `sources_dir`. Each line of
+    /// it is the number of a source and its weight, the sources it doesn't list weigh 1.
+    pub fn load(sources_dir: &Path) -> io::Result<Self> {
+        Ok(Weights {
+            sources_dir: sources_dir.to_path_buf(),
+            weights: Arc::new(RwLock::new(read_weights(sources_dir)?)),
+        })
+    }
+
+    /// Reads the weights files again, the weights stay as they were when one is invalid.
+    pub fn reload(&self) -> io::Result<()> {
+        let weights = read_weights(&self.sources_dir)?;
+        *self.weights.write().unwrap() = weights;
+        Ok(())
+    }
+
+    /// Weights of the sources of `subject` and `kind`, the first one is for source number 1.
+    pub fn get(&self, subject: &str, kind: &str) -> Vec<u32> {
+        self.weights
+            .read()
+            .unwrap()
+            .get(&format!("{subject}/{kind}"))
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+fn read_weights(sources_dir: &Path) -> io::Result<HashMap<String, Vec<u32>>> {
+    let mut weights = HashMap::new();
+    for subject in fs::read_dir(sources_dir)? {
+        let subject = subject?;
+        if !subject.file_type()?.is_dir() {
+            continue;
+        }
+        for kind in fs::read_dir(subject.path())? {
+            let kind = kind?;
+            let path = kind.path().join(WEIGHTS_FILE);
+            if !path.is_file() {
+                continue;
+            }
+            let key = format!(
+                "{}/{}",
+                subject.file_name().to_string_lossy(),
+                kind.file_name().to_string_lossy()
+            );
+            weights.insert(key, parse_weights(&path)?);
+        }
+    }
+    Ok(weights)
+}
+
+/// Parses a weights file, lines are `NUMBER WEIGHT` and anything after a `#` is a comment.
+fn parse_weights(path: &Path) -> io::Result<Vec<u32>> {
+    let mut weights = Vec::new();
+    for (index, line) in fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{}:{}: expected a source number from 1 and its weight",
+                    path.display(),
+                    index + 1
+                ),
+            )
+        };
+        let mut fields = line.split_whitespace().map(str::parse::<u32>);
+        let (Some(Ok(number)), Some(Ok(weight)), None) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            return Err(invalid());
+        };
+        if number == 0 {
+            return Err(invalid());
+        }
+        if number > MAX_WEIGHTED_SOURCE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{}:{}: there can't be weights for sources over {MAX_WEIGHTED_SOURCE}",
+                    path.display(),
+                    index + 1
+                ),
+            ));
+        }
+        if weights.len() < number as usize {
+            weights.resize(number as usize, 1);
+        }
+        weights[number as usize - 1] = weight;
+    }
+    Ok(weights)
+}
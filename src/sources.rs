@@ -0,0 +1,142 @@
+//! The source images there are of each subject and kind, kept up to date with the directory while
+//! the server runs so images can be added or removed without a restart.
+
+use crate::decode;
+use crate::{ImageKind, Subject};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+#[derive(Clone)]
+pub struct Sources {
+    dir: PathBuf,
+    /// The subjects served, the sources of the others are left out.
+    subjects: Arc<[Subject]>,
+    /// Numbers of the sources of each `{subject}/{kind}`, in order.
+    numbers: Arc<RwLock<HashMap<String, Vec<u32>>>>,
+}
+
+impl Sources {
+    /// Lists the sources of `subjects` in `dir`, they are named by their number, like
+    /// `cage/default/7.jpg`.
+    pub fn load(dir: PathBuf, subjects: Vec<Subject>) -> io::Result<Self> {
+        let numbers = scan(&dir, &subjects)?;
+        Ok(Sources {
+            dir,
+            subjects: subjects.into(),
+            numbers: Arc::new(RwLock::new(numbers)),
+        })
+    }
+
+    /// Numbers of the sources of `subject` and `kind`, none when there is no such combination.
+    pub fn numbers(&self, subject: Subject, kind: ImageKind) -> Vec<u32> {
+        self.numbers
+            .read()
+            .unwrap()
+            .get(&format!("{subject}/{kind}"))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Numbers of the sources of every `{subject}/{kind}` there are some of.
+    pub fn all(&self) -> HashMap<String, Vec<u32>> {
+        self.numbers.read().unwrap().clone()
+    }
+
+    /// Where source number `number` of `subject` and `kind` is, or goes.
+    pub fn path(&self, subject: Subject, kind: ImageKind, number: u32) -> PathBuf {
+        self.dir
+            .join(subject.to_string())
+            .join(kind.to_string())
+            .join(format!("{number}.{}", kind.source_extension()))
+    }
+
+    /// What's wrong with the sources of the subjects served, each problem a line of the report:
+    /// a subject without default pictures, pictures missing from the numbers, which go from 1 up,
+    /// and pictures that can't be read. The kinds without a directory are fine, the subject's
+    /// default pictures are served for them.
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for &subject in self.subjects.iter() {
+            if self.numbers(subject, ImageKind::Default).is_empty() {
+                problems.push(format!("{subject}/{} has no pictures", ImageKind::Default));
+            }
+            for kind in ImageKind::ALL {
+                let numbers = self.numbers(subject, kind);
+                let missing: Vec<String> = (1..numbers.last().copied().unwrap_or(0))
+                    .filter(|number| numbers.binary_search(number).is_err())
+                    .map(|number| number.to_string())
+                    .collect();
+                if !missing.is_empty() {
+                    problems.push(format!(
+                        "{subject}/{kind} is missing the pictures {}",
+                        missing.join(", ")
+                    ));
+                }
+                for number in numbers {
+                    let path = self.path(subject, kind, number);
+                    if let Err(e) = decode::open(&path).and_then(|reader| reader.into_dimensions())
+                    {
+                        problems.push(format!("{}: {e}", path.display()));
+                    }
+                }
+            }
+        }
+        problems
+    }
+
+    /// Lists the sources again, for when the directory can't be watched.
+    pub fn reload(&self) -> io::Result<()> {
+        let numbers = scan(&self.dir, &self.subjects)?;
+        *self.numbers.write().unwrap() = numbers;
+        Ok(())
+    }
+
+    /// Lists the sources again whenever something changes in the directory, for as long as the
+    /// returned watcher is kept.
+    pub fn watch(&self) -> notify::Result<RecommendedWatcher> {
+        let sources = self.clone();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<_>| {
+            if event.is_err() {
+                return;
+            }
+            // Half copied directories can fail to list, the next event lists them again.
+            let _ = sources.reload();
+        })?;
+        watcher.watch(&self.dir, RecursiveMode::Recursive)?;
+        Ok(watcher)
+    }
+}
+
+fn scan(dir: &Path, subjects: &[Subject]) -> io::Result<HashMap<String, Vec<u32>>> {
+    let mut numbers = HashMap::new();
+    for &subject in subjects {
+        for kind in ImageKind::ALL {
+            let entries = match fs::read_dir(dir.join(subject.to_string()).join(kind.to_string())) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            let extension = format!(".{}", kind.source_extension());
+            let mut found = Vec::new();
+            for entry in entries {
+                let name = entry?.file_name();
+                let number = name
+                    .to_str()
+                    .and_then(|name| name.strip_suffix(&extension))
+                    .and_then(|number| number.parse::<u32>().ok());
+                if let Some(number @ 1..) = number {
+                    found.push(number);
+                }
+            }
+            if !found.is_empty() {
+                found.sort_unstable();
+                numbers.insert(format!("{subject}/{kind}"), found);
+            }
+        }
+    }
+    Ok(numbers)
+}
@@ -0,0 +1,94 @@
+//! Running as a systemd service: listening on the sockets systemd opened for it, and telling it
+//! when the server is ready and still alive.
+
+use std::env;
+use std::io;
+use std::net::TcpListener;
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use std::process;
+use std::time::Duration;
+
+/// The first socket systemd passes, the others follow it.
+const LISTEN_FDS_START: RawFd = 3;
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// The sockets of the `.socket` unit the server was started by, none when it wasn't. It clears
+/// the variables telling about them, so it has to be called before any thread is started.
+pub fn listeners() -> io::Result<Vec<Listener>> {
+    // Meant for this process only, not one it started or one that inherited the variables.
+    let for_us = env::var("LISTEN_PID").is_ok_and(|pid| pid == process::id().to_string());
+    let count = env::var("LISTEN_FDS").ok().filter(|_| for_us);
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    let Some(count) = count else {
+        return Ok(Vec::new());
+    };
+    let count: RawFd = count.parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("LISTEN_FDS: `{count}` is not a number of sockets"),
+        )
+    })?;
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            // SAFETY: systemd hands these over to the process, nothing else in it uses them.
+            let tcp = unsafe { TcpListener::from_raw_fd(fd) };
+            // Only a TCP socket has an address std can read.
+            match tcp.local_addr() {
+                Ok(_) => Ok(Listener::Tcp(tcp)),
+                Err(_) => Ok(Listener::Unix(unsafe {
+                    UnixListener::from_raw_fd(tcp.into_raw_fd())
+                })),
+            }
+        })
+        .collect()
+}
+
+/// Sends `state`, like `READY=1`, to the service manager. Nothing is sent when the server isn't
+/// run by one.
+pub fn notify(state: &str) {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let sent = UnixDatagram::unbound().and_then(|socket| {
+        // Paths starting with `@` are in the abstract namespace.
+        #[cfg(target_os = "linux")]
+        if let Some(name) = path.as_encoded_bytes().strip_prefix(b"@") {
+            use std::os::linux::net::SocketAddrExt;
+            let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            return socket.send_to_addr(state.as_bytes(), &address);
+        }
+        socket.send_to(state.as_bytes(), &path)
+    });
+    if let Err(e) = sent {
+        log::warn!("could not notify systemd of {state}: {e}");
+    }
+}
+
+/// Tells systemd the server is alive for as long as the runtime it's called on runs, when the
+/// service has a watchdog.
+pub fn start_watchdog() {
+    let for_us = env::var("WATCHDOG_PID").map_or(true, |pid| pid == process::id().to_string());
+    let Some(timeout) = env::var("WATCHDOG_USEC")
+        .ok()
+        .filter(|_| for_us)
+        .and_then(|usec| usec.parse().ok())
+        .map(Duration::from_micros)
+    else {
+        return;
+    };
+    actix_web::rt::spawn(async move {
+        // Twice as often as needed, so one late tick doesn't get the server restarted.
+        let mut ticks = actix_web::rt::time::interval(timeout / 2);
+        loop {
+            ticks.tick().await;
+            notify("WATCHDOG=1");
+        }
+    });
+}
@@ -0,0 +1,284 @@
+//! Text drawn on top of the generated images.
+
+use ab_glyph::{point, Font, FontArc, Glyph, Point, PxScale, ScaleFont};
+use image::{Rgba, RgbaImage};
+use std::collections::HashMap;
+use std::path::Path;
+use std::{fs, io};
+
+/// Font used when none is picked, embedded so the binary doesn't depend on the fonts installed
+/// on the host.
+static DEFAULT_FONT: &[u8] = include_bytes!("../assets/fonts/DejaVuSans-Bold.ttf");
+
+/// Share of the image width the text is allowed to take.
+const MAX_WIDTH_RATIO: f32 = 0.8;
+/// Share of the image height the text is allowed to take.
+const MAX_HEIGHT_RATIO: f32 = 0.3;
+/// Captions run closer to the edges and leave room for each other.
+const CAPTION_MAX_WIDTH_RATIO: f32 = 0.94;
+const CAPTION_MAX_HEIGHT_RATIO: f32 = 0.2;
+const CAPTION_MARGIN_RATIO: f32 = 0.03;
+/// The message of an error image runs under its status, smaller so long messages still fit.
+const ERROR_MESSAGE_MAX_WIDTH_RATIO: f32 = 0.9;
+const ERROR_MESSAGE_MAX_HEIGHT_RATIO: f32 = 0.08;
+
+/// The fonts text can be written with, looked up by their file name without the extension.
+#[derive(Clone)]
+pub struct Fonts {
+    default: FontArc,
+    by_name: HashMap<String, FontArc>,
+}
+
+impl Fonts {
+    /// Loads every TTF and OTF file in `dir`, on top of the embedded default font.
+    pub fn load(dir: Option<&Path>) -> io::Result<Self> {
+        let default = FontArc::try_from_slice(DEFAULT_FONT).expect("the embedded font is valid");
+        let mut by_name = HashMap::new();
+        if let Some(dir) = dir {
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                let is_font = path.extension().is_some_and(|extension| {
+                    extension.eq_ignore_ascii_case("ttf") || extension.eq_ignore_ascii_case("otf")
+                });
+                let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                    continue;
+                };
+                if !is_font {
+                    continue;
+                }
+                let font = FontArc::try_from_vec(fs::read(&path)?).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{}: {e}", path.display()),
+                    )
+                })?;
+                by_name.insert(name.to_lowercase(), font);
+            }
+        }
+        Ok(Fonts { default, by_name })
+    }
+
+    /// The font called `name`, or the default one when no name is given.
+    pub fn get(&self, name: Option<&str>) -> io::Result<FontArc> {
+        match name {
+            None => Ok(self.default.clone()),
+            Some(name) => self
+                .by_name
+                .get(&name.to_lowercase())
+                .cloned()
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("unknown font `{name}`"),
+                    )
+                }),
+        }
+    }
+}
+
+/// Text to write on the image and the font to write it with.
+pub struct TextOverlay {
+    pub text: String,
+    /// Name the font was picked by, `None` for the default font.
+    pub font_name: Option<String>,
+    pub font: FontArc,
+}
+
+/// Meme captions and the text to write in them.
+pub struct Captions {
+    pub top: Option<String>,
+    pub bottom: Option<String>,
+    /// Name the font was picked by, `None` for the default font.
+    pub font_name: Option<String>,
+    pub font: FontArc,
+}
+
+/// Draws `text` on a single line in the middle of `image`, as big as it fits, in white with a
+/// soft shadow so it stays readable on both light and dark pictures.
+pub fn draw_centered(image: &mut RgbaImage, font: &impl Font, text: &str) {
+    let (width, height) = (image.width() as f32, image.height() as f32);
+    let Some(line) = fit(
+        font,
+        text,
+        width * MAX_WIDTH_RATIO,
+        height * MAX_HEIGHT_RATIO,
+    ) else {
+        return;
+    };
+    let origin = point((width - line.width) / 2.0, (height - line.height) / 2.0);
+
+    let shadow = (line.size / 24.0).max(1.0);
+    draw_glyphs(
+        image,
+        font,
+        &line.glyphs,
+        origin + point(shadow, shadow),
+        Rgba([0, 0, 0, 160]),
+    );
+    draw_glyphs(
+        image,
+        font,
+        &line.glyphs,
+        origin,
+        Rgba([255, 255, 255, 255]),
+    );
+}
+
+/// Draws the captions in upper case along the top and bottom edges of `image`, white with a thick
+/// black outline.
+pub fn draw_captions(image: &mut RgbaImage, captions: &Captions) {
+    let (width, height) = (image.width() as f32, image.height() as f32);
+    let margin = height * CAPTION_MARGIN_RATIO;
+    let lines = [(&captions.top, true), (&captions.bottom, false)];
+    for (text, top) in lines {
+        let Some(text) = text else {
+            continue;
+        };
+        let Some(line) = fit(
+            &captions.font,
+            &text.to_uppercase(),
+            width * CAPTION_MAX_WIDTH_RATIO,
+            height * CAPTION_MAX_HEIGHT_RATIO,
+        ) else {
+            continue;
+        };
+        let y = if top {
+            margin
+        } else {
+            height - margin - line.height
+        };
+        let origin = point((width - line.width) / 2.0, y);
+
+        // The outline is the same text stamped all around the final position.
+        let outline = (line.size / 16.0).max(1.0);
+        for step in 0..16 {
+            let angle = step as f32 * std::f32::consts::TAU / 16.0;
+            let offset = point(angle.cos() * outline, angle.sin() * outline);
+            draw_glyphs(
+                image,
+                &captions.font,
+                &line.glyphs,
+                origin + offset,
+                Rgba([0, 0, 0, 255]),
+            );
+        }
+        draw_glyphs(
+            image,
+            &captions.font,
+            &line.glyphs,
+            origin,
+            Rgba([255, 255, 255, 255]),
+        );
+    }
+}
+
+/// Draws the `status` of an error big in the middle of `image` and the `message` under it, in dark
+/// gray for the light gray error images.
+pub fn draw_error(image: &mut RgbaImage, font: &impl Font, status: &str, message: &str) {
+    let (width, height) = (image.width() as f32, image.height() as f32);
+    let color = Rgba([80, 80, 80, 255]);
+    if let Some(line) = fit(
+        font,
+        status,
+        width * MAX_WIDTH_RATIO,
+        height * MAX_HEIGHT_RATIO,
+    ) {
+        let origin = point((width - line.width) / 2.0, height / 2.0 - line.height);
+        draw_glyphs(image, font, &line.glyphs, origin, color);
+    }
+    if let Some(line) = fit(
+        font,
+        message,
+        width * ERROR_MESSAGE_MAX_WIDTH_RATIO,
+        height * ERROR_MESSAGE_MAX_HEIGHT_RATIO,
+    ) {
+        let origin = point((width - line.width) / 2.0, height / 2.0 + line.height / 2.0);
+        draw_glyphs(image, font, &line.glyphs, origin, color);
+    }
+}
+
+/// A line of text laid out at the size it is drawn.
+struct Line {
+    glyphs: Vec<Glyph>,
+    size: f32,
+    width: f32,
+    height: f32,
+}
+
+/// Lays `text` out as big as it can be without going over `max_width` or `max_height`, `None` when
+/// there is nothing to draw.
+fn fit(font: &impl Font, text: &str, max_width: f32, max_height: f32) -> Option<Line> {
+    // Glyph advances grow linearly with the scale, so measuring at 1px is enough to find the
+    // size that fits.
+    let (_, unit_width) = layout(font, PxScale::from(1.0), text);
+    if unit_width <= 0.0 {
+        return None;
+    }
+    let size = (max_width / unit_width).min(max_height);
+    let scale = PxScale::from(size);
+    let scaled = font.as_scaled(scale);
+    let (glyphs, width) = layout(font, scale, text);
+    Some(Line {
+        glyphs,
+        size,
+        width,
+        height: scaled.ascent() - scaled.descent(),
+    })
+}
+
+/// Positions the glyphs of `text` on a line starting at the origin, returning them with the
+/// width of the line.
+fn layout(font: &impl Font, scale: PxScale, text: &str) -> (Vec<Glyph>, f32) {
+    let font = font.as_scaled(scale);
+    let mut caret = 0.0;
+    let mut previous = None;
+    let glyphs = text
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| {
+            let id = font.glyph_id(c);
+            if let Some(previous) = previous {
+                caret += font.kern(previous, id);
+            }
+            let glyph = id.with_scale_and_position(scale, point(caret, font.ascent()));
+            caret += font.h_advance(id);
+            previous = Some(id);
+            glyph
+        })
+        .collect();
+    (glyphs, caret)
+}
+
+fn draw_glyphs(
+    image: &mut RgbaImage,
+    font: &impl Font,
+    glyphs: &[Glyph],
+    offset: Point,
+    color: Rgba<u8>,
+) {
+    for glyph in glyphs {
+        let mut glyph = glyph.clone();
+        glyph.position += offset;
+        let Some(outlined) = font.outline_glyph(glyph) else {
+            continue;
+        };
+        let bounds = outlined.px_bounds();
+        outlined.draw(|x, y, coverage| {
+            let x = bounds.min.x as i64 + i64::from(x);
+            let y = bounds.min.y as i64 + i64::from(y);
+            if x < 0 || y < 0 || x >= i64::from(image.width()) || y >= i64::from(image.height()) {
+                return;
+            }
+            blend(image.get_pixel_mut(x as u32, y as u32), color, coverage);
+        });
+    }
+}
+
+fn blend(pixel: &mut Rgba<u8>, color: Rgba<u8>, coverage: f32) {
+    let alpha = coverage.clamp(0.0, 1.0) * f32::from(color[3]) / 255.0;
+    for i in 0..3 {
+        pixel[i] =
+            (f32::from(pixel[i]) * (1.0 - alpha) + f32::from(color[i]) * alpha).round() as u8;
+    }
+    pixel[3] = pixel[3].max((alpha * 255.0).round() as u8);
+}
@@ -0,0 +1,42 @@
+//! Serving HTTPS without a proxy in front, for small deployments.
+
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+
+/// TLS with the PEM certificate chain in `cert` and the PEM private key in `key`.
+pub fn config(cert: &Path, key: &Path) -> io::Result<ServerConfig> {
+    let with_path = |path: &Path| {
+        let path = path.display().to_string();
+        move |e: io::Error| io::Error::new(e.kind(), format!("{path}: {e}"))
+    };
+    let certs = rustls_pemfile::certs(&mut BufReader::new(
+        File::open(cert).map_err(with_path(cert))?,
+    ))
+    .collect::<io::Result<Vec<CertificateDer>>>()
+    .map_err(with_path(cert))?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: there is no certificate in it", cert.display()),
+        ));
+    }
+    let key: PrivateKeyDer = rustls_pemfile::private_key(&mut BufReader::new(
+        File::open(key).map_err(with_path(key))?,
+    ))
+    .map_err(with_path(key))?
+    .ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: there is no private key in it", key.display()),
+        )
+    })?;
+
+    ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
@@ -0,0 +1,185 @@
+//! Making the images of the usual sizes before anyone asks for them, so the first requests for
+//! them don't wait on the resize, either at startup or ahead of time with the `pregen` command.
+
+use crate::cache::{self, CacheBackend, CacheConfig, CacheKind};
+use crate::effects::Effects;
+use crate::error::PlacecageError;
+use crate::resize::Resize;
+use crate::selection::Selection;
+use crate::{
+    cache_key, get_image, resize_filter, select_source, Encoding, ImageKind, ImageRequest,
+    OutputFormat, Settings, Subject,
+};
+use clap::Args;
+use std::io;
+
+/// Parses a comma separated list of sizes, like `300x200,640x480`.
+pub fn parse_sizes(value: &str) -> io::Result<Vec<(u32, u32)>> {
+    value
+        .split(',')
+        .map(|size| {
+            let size = size.trim();
+            size.split_once('x')
+                .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("`{size}` is not a size like 300x200"),
+                    )
+                })
+        })
+        .collect()
+}
+
+#[derive(Args)]
+pub struct PregenArgs {
+    /// Sizes to make, like 300x200,640x480. PLACECAGE_WARM_SIZES by default.
+    #[arg(long)]
+    sizes: Option<String>,
+    /// Subjects to make them of, like cage,murray. All of them by default.
+    #[arg(long)]
+    subjects: Option<String>,
+}
+
+/// Makes the images into the generated images directory and exits, to ship them with the server.
+pub fn pregen(settings: &Settings, args: PregenArgs) -> io::Result<()> {
+    let sizes = match &args.sizes {
+        Some(sizes) => parse_sizes(sizes)?,
+        None => settings.warm_sizes.clone(),
+    };
+    let subjects = match &args.subjects {
+        Some(subjects) => parse_subjects(subjects)?,
+        None => Subject::ALL.to_vec(),
+    };
+    if sizes.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "pregen needs --sizes or PLACECAGE_WARM_SIZES",
+        ));
+    }
+
+    let cache = cache::open(&CacheConfig {
+        kinds: vec![CacheKind::Fs],
+        ..settings.cache.clone()
+    })?;
+    match warm(settings, &*cache, &sizes, &subjects) {
+        0 => Ok(()),
+        failed => Err(io::Error::other(format!(
+            "{failed} images could not be made"
+        ))),
+    }
+}
+
+/// Parses a comma separated list of subjects, like `cage,murray`.
+pub fn parse_subjects(value: &str) -> io::Result<Vec<Subject>> {
+    value
+        .split(',')
+        .map(|name| {
+            let name = name.trim();
+            Subject::ALL
+                .into_iter()
+                .find(|subject| subject.to_string() == name)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("unknown subject `{name}`"),
+                    )
+                })
+        })
+        .collect()
+}
+
+/// Makes the images of every kind of `subjects` at `sizes` into `cache`, as they are served to
+/// requests without any options. Still images are made as JPEG and WebP, what the clients get
+/// unless they support AVIF, and the gifs stay animated. Returns how many couldn't be made.
+pub fn warm(
+    settings: &Settings,
+    cache: &dyn CacheBackend,
+    sizes: &[(u32, u32)],
+    subjects: &[Subject],
+) -> usize {
+    let mut failed = 0;
+    for &subject in subjects {
+        for kind in ImageKind::ALL {
+            if settings.sources.numbers(subject, kind).is_empty() {
+                continue;
+            }
+            let formats: &[OutputFormat] = match kind {
+                ImageKind::Gif => &[OutputFormat::Gif],
+                _ => &[OutputFormat::Jpeg, OutputFormat::Webp],
+            };
+            for &(width, height) in sizes {
+                for &format in formats {
+                    if let Err(e) =
+                        warm_image(settings, cache, subject, kind, width, height, format)
+                    {
+                        log::warn!(
+                            "could not make {subject}/{kind} at {width}x{height} as {format}: {e}"
+                        );
+                        failed += 1;
+                    }
+                }
+            }
+        }
+    }
+    failed
+}
+
+fn warm_image(
+    settings: &Settings,
+    cache: &dyn CacheBackend,
+    subject: Subject,
+    kind: ImageKind,
+    width: u32,
+    height: u32,
+    format: OutputFormat,
+) -> Result<(), PlacecageError> {
+    let image = ImageRequest {
+        width: Some(width),
+        height: Some(height),
+        subject: Some(subject),
+        kind: Some(kind),
+        format: None,
+        poster: false,
+        grayscale: false,
+        captions: None,
+        seed: None,
+    };
+    let source = select_source(
+        &image,
+        &Selection::default(),
+        &settings.sources,
+        &settings.weights,
+    )?;
+    let resize = Resize {
+        width,
+        height,
+        fit: Default::default(),
+        pad: None,
+        gravity: Default::default(),
+        focal: None,
+        crop: None,
+        filter: resize_filter(width, height, settings.limits),
+        resample: None,
+        gpu: settings.limits.on_gpu(width, height),
+    };
+    let encoding = Encoding {
+        format,
+        quality: None,
+        progressive: false,
+        chroma: None,
+        jpeg_backend: settings.jpeg_backend,
+    };
+    let effects = Effects::default();
+    let key = cache_key(
+        &image,
+        1.0,
+        &source,
+        resize,
+        &effects,
+        encoding,
+        settings.limits,
+    )?;
+    get_image(&image, &source, resize, &effects, encoding, &key, cache)?;
+    Ok(())
+}